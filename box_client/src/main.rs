@@ -2,12 +2,72 @@ extern crate specs;
 extern crate libbox;
 extern crate time;
 
+use std::env;
+use std::net::{Ipv4Addr, SocketAddr};
+
 
 fn main() {
-    let cfg = libbox::client::ClientConfig::new();
+    let mut cfg = libbox::client::ClientConfig::new();
+
+    // box_client [--name <name>] [--password <password>] [--noise [--server-key <hex>]]
+    //            [--bindings <path>]
+    if let Some(name) = env::args().skip_while(|arg| arg != "--name").nth(1) {
+        cfg.player_name = name;
+    }
+    cfg.auth_token = env::args().skip_while(|arg| arg != "--password").nth(1);
+    cfg.bindings_file = env::args().skip_while(|arg| arg != "--bindings").nth(1).map(std::path::PathBuf::from);
+    if env::args().any(|arg| arg == "--noise") {
+        let server_key = env::args().skip_while(|arg| arg != "--server-key").nth(1)
+            .map(|key| libbox::client::decode_key(&key).expect("Invalid server key"));
+        cfg.encryption = libbox::client::ClientEncryption::Noise(server_key);
+    }
+
+    // box_client --list: print the servers on the LAN and exit
+    // box_client --lan: join the first server found on the LAN
+    let list = env::args().any(|arg| arg == "--list");
+    if list || env::args().any(|arg| arg == "--lan") {
+        let broadcast = SocketAddr::new(Ipv4Addr::new(255, 255, 255, 255).into(), libbox::client::DISCOVERY_PORT);
+        let servers = match libbox::client::discover_servers(broadcast, std::time::Duration::from_secs(1)) {
+            Ok(servers) => servers,
+            Err(error) => {
+                println!("LAN discovery failed: {:?}", error);
+                return;
+            }
+        };
+
+        if list {
+            for server in &servers {
+                println!("{} - {} (version {}), {}/{} players", server.address, server.info.name,
+                         server.info.version, server.info.player_count, server.info.capacity);
+            }
+            println!("{} servers found", servers.len());
+            return;
+        }
+
+        match servers.first() {
+            Some(server) => cfg.server_address = server.address,
+            None => {
+                println!("No servers found on the LAN");
+                return;
+            }
+        }
+    }
+
+    // box_client --info: print the server's info and exit without joining
+    if env::args().any(|arg| arg == "--info") {
+        let timeout = std::time::Duration::from_secs(2);
+        match libbox::client::query_server_info(cfg.server_address, cfg.encryption.clone(), timeout) {
+            Ok(info) => {
+                println!("{} (version {}, protocols {}-{})", info.name, info.version, info.protocols.min, info.protocols.max);
+                println!("{}/{} players, up for {}s", info.player_count, info.capacity, info.uptime_secs);
+            }
+            Err(error) => println!("Could not query server at {}: {:?}", cfg.server_address, error),
+        }
+        return;
+    }
     let timestep = cfg.timestep;
     let sim_rate = cfg.sim_rate;
-    let system_planner = libbox::client::make_client_world(cfg);
+    let system_planner = libbox::client::make_client_world(cfg.clone());
     let mut game = libbox::client::ClientGame::new(system_planner, cfg);
 
 
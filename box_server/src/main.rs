@@ -1,19 +1,66 @@
 extern crate specs;
 extern crate libbox;
 extern crate time;
+extern crate ctrlc;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
 fn main() {
-    let cfg = libbox::server::ServerConfig::new();
+    // box_server [--listen <address:port>] [--save <path>] [--scenario <path>] [--motd <path>]
+    //            [--password <password> | --users <path>]
+    //            [--noise [--noise-key <hex>]] [--replication-stats <seconds>]
+    let listen = env::args().skip_while(|arg| arg != "--listen").nth(1)
+        .map(|address| address.parse().expect("--listen takes an address and port, e.g. 0.0.0.0:8844"));
+    let save_path = env::args().skip_while(|arg| arg != "--save").nth(1);
+    let scenario_path = env::args().skip_while(|arg| arg != "--scenario").nth(1);
+    let motd_path = env::args().skip_while(|arg| arg != "--motd").nth(1);
+    let password = env::args().skip_while(|arg| arg != "--password").nth(1);
+    let users_path = env::args().skip_while(|arg| arg != "--users").nth(1);
+    let stats_interval = env::args().skip_while(|arg| arg != "--replication-stats").nth(1)
+        .map(|secs| secs.parse::<i64>().expect("--replication-stats takes a number of seconds"));
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))
+            .expect("Failed to set SIGINT/SIGTERM handler");
+    }
+
+    let mut cfg = libbox::server::ServerConfig::new();
+    if let Some(address) = listen {
+        cfg.server_address = address;
+    }
+    cfg.motd_file = motd_path.map(PathBuf::from);
+    cfg.scenario = scenario_path.map(PathBuf::from);
+    if let Some(path) = users_path {
+        cfg.auth = libbox::server::AuthConfig::UserFile(PathBuf::from(path));
+    }
+    else if let Some(password) = password {
+        cfg.auth = libbox::server::AuthConfig::Password(password);
+    }
+    if env::args().any(|arg| arg == "--noise") {
+        let key = env::args().skip_while(|arg| arg != "--noise-key").nth(1)
+            .map(|key| libbox::server::decode_key(&key).expect("Invalid noise key"));
+        cfg.encryption = libbox::server::ServerEncryption::Noise(key);
+    }
+    cfg.replication_report_interval = stats_interval.map(time::Duration::seconds);
     let timestep = cfg.timestep;
     let sim_rate = cfg.sim_rate;
-    let system_planner = libbox::server::make_server_world(cfg);
+    let system_planner = libbox::server::make_server_world(cfg.clone());
     let mut game = libbox::server::ServerGame::new(system_planner, cfg);
 
 
     let mut dt = timestep;
     let mut t = time::PreciseTime::now();
     while game.is_running() {
+        if interrupted.load(Ordering::SeqCst) {
+            game.stop();
+            break;
+        }
         game.run(dt);
         let now = time::PreciseTime::now();
         dt = t.to(now);
@@ -23,5 +70,8 @@ fn main() {
         t = now;
     }
 
-    // game.do_cleanup(); // TODO
+    println!("shutting down");
+    if let Err(error) = game.shutdown(save_path.as_ref().map(Path::new)) {
+        println!("failed to save world: {:?}", error);
+    }
 }
@@ -0,0 +1,404 @@
+//! Which keys and mouse buttons do what. Each context has its own map from inputs to actions, so
+//! a key can mean one thing in game and another while typing in chat.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use glium::glutin::MouseButton;
+use glium::glutin::VirtualKeyCode as KC;
+
+/// Which set of bindings is in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Keybinds {
+    InGame,
+    /// Typing a chat message
+    Menu,
+}
+
+impl Keybinds {
+    fn name(&self) -> &'static str {
+        match *self {
+            Keybinds::InGame => "ingame",
+            Keybinds::Menu => "menu",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Keybinds> {
+        match name {
+            "ingame" => Some(Keybinds::InGame),
+            "menu" => Some(Keybinds::Menu),
+            _ => None,
+        }
+    }
+}
+
+/// Something the player can do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    OpenChat,
+    /// Select what's under the cursor, or drag out a rectangle
+    Select,
+    AddToSelection,
+    ToggleSelection,
+    /// Send the selection to the cursor
+    Command,
+    /// Like `Command`, after whatever they're already doing
+    QueueCommand,
+    /// Select a control group, 1 to 9
+    RecallGroup(u8),
+    /// Add a control group to the selection
+    AddGroup(u8),
+    /// Save the selection as a control group
+    StoreGroup(u8),
+    SendChat,
+    CancelChat,
+    /// Delete the last character typed
+    DeleteChar,
+    /// Move the view while held
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    ZoomIn,
+    ZoomOut,
+    /// Drag the ground around with the cursor while held
+    DragPan,
+    /// Keep the camera on the selection, or stop
+    ToggleFollow,
+}
+
+impl Action {
+    fn name(&self) -> String {
+        match *self {
+            Action::Quit => "quit".to_owned(),
+            Action::OpenChat => "open_chat".to_owned(),
+            Action::Select => "select".to_owned(),
+            Action::AddToSelection => "add_to_selection".to_owned(),
+            Action::ToggleSelection => "toggle_selection".to_owned(),
+            Action::Command => "command".to_owned(),
+            Action::QueueCommand => "queue_command".to_owned(),
+            Action::RecallGroup(n) => format!("recall_group_{}", n),
+            Action::AddGroup(n) => format!("add_group_{}", n),
+            Action::StoreGroup(n) => format!("store_group_{}", n),
+            Action::SendChat => "send_chat".to_owned(),
+            Action::CancelChat => "cancel_chat".to_owned(),
+            Action::DeleteChar => "delete_char".to_owned(),
+            Action::PanUp => "pan_up".to_owned(),
+            Action::PanDown => "pan_down".to_owned(),
+            Action::PanLeft => "pan_left".to_owned(),
+            Action::PanRight => "pan_right".to_owned(),
+            Action::ZoomIn => "zoom_in".to_owned(),
+            Action::ZoomOut => "zoom_out".to_owned(),
+            Action::DragPan => "drag_pan".to_owned(),
+            Action::ToggleFollow => "toggle_follow".to_owned(),
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Action> {
+        let group = |prefix: &str| {
+            if !name.starts_with(prefix) {
+                return None;
+            }
+            name[prefix.len()..].parse::<u8>().ok().and_then(|n| if n >= 1 && n <= 9 { Some(n) } else { None })
+        };
+        let action = match name {
+            "quit" => Action::Quit,
+            "open_chat" => Action::OpenChat,
+            "select" => Action::Select,
+            "add_to_selection" => Action::AddToSelection,
+            "toggle_selection" => Action::ToggleSelection,
+            "command" => Action::Command,
+            "queue_command" => Action::QueueCommand,
+            "send_chat" => Action::SendChat,
+            "cancel_chat" => Action::CancelChat,
+            "delete_char" => Action::DeleteChar,
+            "pan_up" => Action::PanUp,
+            "pan_down" => Action::PanDown,
+            "pan_left" => Action::PanLeft,
+            "pan_right" => Action::PanRight,
+            "zoom_in" => Action::ZoomIn,
+            "zoom_out" => Action::ZoomOut,
+            "drag_pan" => Action::DragPan,
+            "toggle_follow" => Action::ToggleFollow,
+            _ => {
+                return group("recall_group_").map(Action::RecallGroup)
+                    .or_else(|| group("add_group_").map(Action::AddGroup))
+                    .or_else(|| group("store_group_").map(Action::StoreGroup));
+            }
+        };
+        Some(action)
+    }
+}
+
+/// A key or mouse button, or a turn of the mouse wheel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    Key(KC),
+    Mouse(MouseButton),
+    /// The wheel turned away from the player
+    WheelUp,
+    WheelDown,
+}
+
+/// Modifier keys that have to be held, and no others.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub fn none() -> Modifiers {
+        Modifiers::default()
+    }
+
+    pub fn shift() -> Modifiers {
+        Modifiers { shift: true, .. Modifiers::default() }
+    }
+
+    pub fn ctrl() -> Modifiers {
+        Modifiers { ctrl: true, .. Modifiers::default() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Input {
+    pub trigger: Trigger,
+    pub modifiers: Modifiers,
+}
+
+impl Input {
+    pub fn new(trigger: Trigger, modifiers: Modifiers) -> Input {
+        Input {
+            trigger: trigger,
+            modifiers: modifiers,
+        }
+    }
+
+    /// Written like `Ctrl+Shift+Key1`, `Mouse:Left` or `Wheel:Up`.
+    pub fn parse(text: &str) -> Option<Input> {
+        let mut modifiers = Modifiers::none();
+        let mut parts: Vec<&str> = text.split('+').collect();
+        let trigger = match parts.pop() {
+            Some(trigger) => trigger,
+            None => return None,
+        };
+        for part in parts {
+            match part {
+                "Shift" => modifiers.shift = true,
+                "Ctrl" => modifiers.ctrl = true,
+                "Alt" => modifiers.alt = true,
+                _ => return None,
+            }
+        }
+
+        let trigger = if trigger.starts_with("Mouse:") {
+            match &trigger["Mouse:".len()..] {
+                "Left" => Trigger::Mouse(MouseButton::Left),
+                "Right" => Trigger::Mouse(MouseButton::Right),
+                "Middle" => Trigger::Mouse(MouseButton::Middle),
+                other => match other.parse::<u8>() {
+                    Ok(n) => Trigger::Mouse(MouseButton::Other(n)),
+                    Err(_) => return None,
+                },
+            }
+        }
+        else if trigger == "Wheel:Up" {
+            Trigger::WheelUp
+        }
+        else if trigger == "Wheel:Down" {
+            Trigger::WheelDown
+        }
+        else {
+            match KEYS.iter().find(|key| format!("{:?}", key) == trigger) {
+                Some(&key) => Trigger::Key(key),
+                None => return None,
+            }
+        };
+        Some(Input::new(trigger, modifiers))
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.modifiers.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.modifiers.shift {
+            write!(f, "Shift+")?;
+        }
+        match self.trigger {
+            Trigger::Key(key) => write!(f, "{:?}", key),
+            Trigger::Mouse(MouseButton::Other(n)) => write!(f, "Mouse:{}", n),
+            Trigger::Mouse(button) => write!(f, "Mouse:{:?}", button),
+            Trigger::WheelUp => write!(f, "Wheel:Up"),
+            Trigger::WheelDown => write!(f, "Wheel:Down"),
+        }
+    }
+}
+
+/// Keys that can be bound, by the names they're written with.
+const KEYS: &'static [KC] = &[
+    KC::Key1, KC::Key2, KC::Key3, KC::Key4, KC::Key5, KC::Key6, KC::Key7, KC::Key8, KC::Key9, KC::Key0,
+    KC::A, KC::B, KC::C, KC::D, KC::E, KC::F, KC::G, KC::H, KC::I, KC::J, KC::K, KC::L, KC::M,
+    KC::N, KC::O, KC::P, KC::Q, KC::R, KC::S, KC::T, KC::U, KC::V, KC::W, KC::X, KC::Y, KC::Z,
+    KC::F1, KC::F2, KC::F3, KC::F4, KC::F5, KC::F6, KC::F7, KC::F8, KC::F9, KC::F10, KC::F11, KC::F12,
+    KC::Escape, KC::Return, KC::Space, KC::Back, KC::Tab, KC::Delete, KC::Insert,
+    KC::Home, KC::End, KC::PageUp, KC::PageDown, KC::Left, KC::Right, KC::Up, KC::Down,
+    KC::Grave, KC::Minus, KC::Equals, KC::Comma, KC::Period, KC::Slash, KC::Semicolon, KC::Apostrophe,
+    KC::LBracket, KC::RBracket, KC::Backslash,
+];
+
+/// A line of a bindings file: `context action input`, e.g. `ingame queue_command Shift+Mouse:Right`.
+pub fn parse_binding(line: &str) -> Result<(Keybinds, Action, Input), String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err("expected `context action input`".to_owned());
+    }
+    let context = Keybinds::from_name(parts[0]).ok_or_else(|| format!("unknown context `{}`", parts[0]))?;
+    let action = Action::from_name(parts[1]).ok_or_else(|| format!("unknown action `{}`", parts[1]))?;
+    let input = Input::parse(parts[2]).ok_or_else(|| format!("unknown input `{}`", parts[2]))?;
+    Ok((context, action, input))
+}
+
+/// What each input does, in each context.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    maps: HashMap<Keybinds, HashMap<Input, Action>>,
+}
+
+impl Bindings {
+    pub fn empty() -> Bindings {
+        Bindings {
+            maps: HashMap::new(),
+        }
+    }
+
+    /// Load bindings from a file with one binding per line, as read by `parse_binding`. Blank
+    /// lines and lines starting with `#` are ignored. Actions the file doesn't mention keep their
+    /// default bindings.
+    pub fn load(path: &Path) -> io::Result<Bindings> {
+        let file = BufReader::new(File::open(path)?);
+
+        let mut loaded = Vec::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_binding(line) {
+                Ok(binding) => loaded.push(binding),
+                Err(error) => {
+                    let msg = format!("{:?} line {}: {}", path, i + 1, error);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+
+        let mut bindings = Bindings::default();
+        let mentioned: HashSet<(Keybinds, Action)> = loaded.iter().map(|&(context, action, _)| (context, action)).collect();
+        for &(context, action) in &mentioned {
+            bindings.unbind(context, action);
+        }
+        for (context, action, input) in loaded {
+            bindings.bind(context, action, input);
+        }
+        Ok(bindings)
+    }
+
+    /// Write every binding to `path`, in the format `load` reads.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut lines: Vec<String> = self.maps.iter()
+            .flat_map(|(context, map)| map.iter().map(move |(input, action)| {
+                format!("{} {} {}", context.name(), action.name(), input)
+            }))
+            .collect();
+        lines.sort();
+
+        let mut file = File::create(path)?;
+        writeln!(file, "# context action input")?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// What `input` does in `context`, if anything.
+    pub fn action(&self, context: Keybinds, input: &Input) -> Option<Action> {
+        self.maps.get(&context).and_then(|map| map.get(input)).cloned()
+    }
+
+    /// Make `input` do `action` in `context`, as well as whatever else is bound to `action`. If
+    /// `input` did something else before, it doesn't anymore.
+    pub fn bind(&mut self, context: Keybinds, action: Action, input: Input) {
+        self.maps.entry(context).or_insert_with(HashMap::new).insert(input, action);
+    }
+
+    /// Remove every input bound to `action` in `context`.
+    pub fn unbind(&mut self, context: Keybinds, action: Action) {
+        if let Some(map) = self.maps.get_mut(&context) {
+            map.retain(|_, bound| *bound != action);
+        }
+    }
+
+    /// Make `input` the only way to do `action` in `context`.
+    pub fn rebind(&mut self, context: Keybinds, action: Action, input: Input) {
+        self.unbind(context, action);
+        self.bind(context, action, input);
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        use self::Keybinds::*;
+
+        let key = |key: KC| Input::new(Trigger::Key(key), Modifiers::none());
+        let mouse = |button: MouseButton, modifiers: Modifiers| Input::new(Trigger::Mouse(button), modifiers);
+
+        let mut bindings = Bindings::empty();
+        bindings.bind(InGame, Action::Quit, key(KC::Escape));
+        bindings.bind(InGame, Action::OpenChat, key(KC::Return));
+        bindings.bind(InGame, Action::Select, mouse(MouseButton::Left, Modifiers::none()));
+        bindings.bind(InGame, Action::AddToSelection, mouse(MouseButton::Left, Modifiers::shift()));
+        bindings.bind(InGame, Action::ToggleSelection, mouse(MouseButton::Left, Modifiers::ctrl()));
+        bindings.bind(InGame, Action::Command, mouse(MouseButton::Right, Modifiers::none()));
+        bindings.bind(InGame, Action::QueueCommand, mouse(MouseButton::Right, Modifiers::shift()));
+
+        let numbers = [KC::Key1, KC::Key2, KC::Key3, KC::Key4, KC::Key5, KC::Key6, KC::Key7, KC::Key8, KC::Key9];
+        for (i, &number) in numbers.iter().enumerate() {
+            let group = i as u8 + 1;
+            bindings.bind(InGame, Action::RecallGroup(group), key(number));
+            bindings.bind(InGame, Action::AddGroup(group), Input::new(Trigger::Key(number), Modifiers::shift()));
+            bindings.bind(InGame, Action::StoreGroup(group), Input::new(Trigger::Key(number), Modifiers::ctrl()));
+        }
+
+        bindings.bind(InGame, Action::PanUp, key(KC::W));
+        bindings.bind(InGame, Action::PanUp, key(KC::Up));
+        bindings.bind(InGame, Action::PanDown, key(KC::S));
+        bindings.bind(InGame, Action::PanDown, key(KC::Down));
+        bindings.bind(InGame, Action::PanLeft, key(KC::A));
+        bindings.bind(InGame, Action::PanLeft, key(KC::Left));
+        bindings.bind(InGame, Action::PanRight, key(KC::D));
+        bindings.bind(InGame, Action::PanRight, key(KC::Right));
+        bindings.bind(InGame, Action::ZoomIn, Input::new(Trigger::WheelUp, Modifiers::none()));
+        bindings.bind(InGame, Action::ZoomIn, key(KC::Equals));
+        bindings.bind(InGame, Action::ZoomOut, Input::new(Trigger::WheelDown, Modifiers::none()));
+        bindings.bind(InGame, Action::ZoomOut, key(KC::Minus));
+        bindings.bind(InGame, Action::DragPan, mouse(MouseButton::Middle, Modifiers::none()));
+        bindings.bind(InGame, Action::ToggleFollow, key(KC::F));
+
+        bindings.bind(Menu, Action::SendChat, key(KC::Return));
+        bindings.bind(Menu, Action::CancelChat, key(KC::Escape));
+        bindings.bind(Menu, Action::DeleteChar, key(KC::Back));
+        bindings
+    }
+}
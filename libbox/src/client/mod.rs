@@ -1,4 +1,6 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use time::Duration;
 
@@ -10,11 +12,19 @@ use nalgebra::Point2;
 mod systems;
 use self::systems::*;
 
+mod bindings;
+pub use self::bindings::{Action, Bindings, Input, Keybinds, Modifiers, Trigger};
+
+mod query;
+pub use self::query::query_server_info;
+pub use common::discovery::{discover_servers, DiscoveredServer, DISCOVERY_PORT};
+pub use common::net::{decode_key, ClientEncryption};
+
 use common::Message;
 use common::resources::*;
 use common::components::*;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct ClientConfig {
     pub timestep: Duration,
     pub sim_rate: Duration,
@@ -22,6 +32,25 @@ pub struct ClientConfig {
     pub window_height: u32,
     pub fov: f32,
     pub server_address: SocketAddr,
+    pub player_name: String,
+    /// Password for servers that require one
+    pub auth_token: Option<String>,
+    pub encryption: ClientEncryption,
+    /// How often to ping the server
+    pub heartbeat_interval: Duration,
+    /// Give up on the server if we haven't heard from it in this long
+    pub idle_timeout: Duration,
+    /// Key and mouse bindings are read from this file, if it exists, on top of the defaults.
+    /// Bindings changed in game are saved back to it.
+    pub bindings_file: Option<PathBuf>,
+    /// How fast the camera pans, as a multiple of its distance from the ground per second
+    pub camera_pan_speed: f32,
+    pub camera_min_distance: f32,
+    pub camera_max_distance: f32,
+    /// How much closer each step of zoom takes the camera, as a fraction of its distance
+    pub camera_zoom_step: f32,
+    /// The view scrolls while the cursor is within this many pixels of the edge of the window
+    pub edge_scroll_margin: u32,
     // data directories, etc
 }
 
@@ -35,6 +64,17 @@ impl ClientConfig {
             window_height: 720,
             fov: FRAC_PI_4,
             server_address: "127.0.0.1:8844".parse().unwrap(),
+            player_name: "player".to_owned(),
+            auth_token: None,
+            encryption: ClientEncryption::Plaintext,
+            heartbeat_interval: Duration::seconds(1),
+            idle_timeout: Duration::seconds(10),
+            bindings_file: None,
+            camera_pan_speed: 1.0,
+            camera_min_distance: 10.0,
+            camera_max_distance: 90.0,
+            camera_zoom_step: 0.1,
+            edge_scroll_margin: 10,
         }
     }
 }
@@ -59,33 +99,30 @@ pub fn make_client_world(cfg: ClientConfig) -> specs::Planner<Message, ClientSys
 
     world.register::<Render>();
     world.register::<Movement>();
+    world.register::<MovementStats>();
     world.register::<Selection>();
     world.register::<Controllable>();
 
-    world.create_now().with(Render::new()).with(Movement::new()).with(Selection::new()).with(Controllable::new()).build();
-
-    for i in 0..50 {
-        let x = ((((17*i+73)%80)-40) as f32)/2.0;
-        let y = ((((3*i+45)%50)-25) as f32)/2.0;
-        let pos = Movement::new_pos(Point3::new(x, y, 0.0));
-        world.create_now().with(Render::new()).with(pos).with(Selection::new()).with(Controllable::new()).build();
-    }
-
-    // start at +5, move to -5
-    use nalgebra::Point3;
-    let mvmnt = Movement::new_pos_target(Point3::new(5.0, 0.0, 0.0), Point3::new(-5.0, 0.0, 0.0));
-    world.create_now().with(Render::new()).with(mvmnt).with(Selection::new()).build();
+    // entities are created as the server tells us about them
 
     world.add_resource(IsRunning(true));
     world.add_resource(Camera::new(cfg.window_width, cfg.window_height, cfg.fov));
+    world.add_resource(CameraControl::new());
     world.add_resource(CursorPosition(Point2::new(0,0)));
-    world.add_resource(CurrentSelection(None));
+    world.add_resource(CurrentSelection(HashSet::new()));
+    world.add_resource(DragSelection(None));
+    world.add_resource(ControlGroups::new());
     world.add_resource(CurrentHover::None);
+    world.add_resource(ChatLog::new(100));
+    world.add_resource(Latency(None));
+    world.add_resource(ReplicatedEntities::new());
 
     let mut p = specs::Planner::new(world, 4);
     p.add_system(SelectionSystem::new(), "selection", 1);
     p.add_system(MovementSystem::new(), "movement", 2);
+    p.add_system(CameraSystem::new(&cfg), "camera", 5);
     p.add_system(NetworkSystem::new(cfg), "network", 20);
+    p.add_system(ReplicationSystem::new(), "replication", 15);
 
     p
 }
@@ -101,8 +138,15 @@ pub struct ClientGame {
 
 impl ClientGame {
     pub fn new(planner: specs::Planner<Message, ClientSystemContext>, cfg: ClientConfig) -> ClientGame {
-        let input = InputSystem::new();
-        let mut window = RenderSystem::new_window(cfg);
+        let bindings = match cfg.bindings_file {
+            Some(ref path) if path.exists() => Bindings::load(path).unwrap_or_else(|error| {
+                println!("failed to load bindings, using the defaults: {:?}", error);
+                Bindings::default()
+            }),
+            _ => Bindings::default(),
+        };
+        let input = InputSystem::new(bindings, cfg.bindings_file.clone());
+        let mut window = RenderSystem::new_window(&cfg);
         let render = RenderSystem::new(&mut window);
 
         let ctx = ClientSystemContext::new(Duration::seconds(0), cfg.timestep);
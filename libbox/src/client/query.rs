@@ -0,0 +1,38 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use common::{NetworkMessage, ServerInfo};
+use common::net::{ClientEncryption, Connection};
+
+/// Ask the server at `address` for its `ServerInfo` without joining the game.
+///
+/// This blocks for up to `timeout` on each of connecting and reading the response.
+pub fn query_server_info(address: SocketAddr, encryption: ClientEncryption, timeout: Duration) -> io::Result<ServerInfo> {
+    let stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+
+    let mut connection = match encryption {
+        ClientEncryption::Plaintext => Connection::plaintext(stream),
+        ClientEncryption::Noise(server_key) => Connection::noise_initiator(stream, server_key)?,
+    };
+    connection.send(&NetworkMessage::ServerInfoRequest)?;
+
+    // the server closes the connection after answering a query, so we get an eof error rather
+    // than waiting forever if it never answers
+    loop {
+        if connection.receive()? == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no response to info query"));
+        }
+
+        match connection.next_message() {
+            Some(Ok(NetworkMessage::ServerInfoResponse(info))) => return Ok(info),
+            Some(Ok(other)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("unexpected response to info query: {:?}", other)));
+            }
+            Some(Err(error)) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            None => (), // partial message or still handshaking, keep reading
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use specs::{MessageQueue, RunArg, System, World};
+
+use nalgebra;
+use nalgebra::{Norm, Point3, Vector2, Vector3};
+
+use client::{ClientConfig, ClientSystemContext};
+
+use common::Message;
+use common::components::Movement;
+use common::resources::{Camera, CameraControl, CurrentSelection, CursorPosition};
+
+/// Moves the camera around as asked through `CameraControl`: panning with keys or by pushing the
+/// cursor against the edge of the screen, dragging the ground around with the mouse, zooming,
+/// and following the selection.
+pub struct CameraSystem {
+    /// Fraction of the camera's distance from the ground covered per ms when panning
+    pan_speed: f32,
+    min_distance: f32,
+    max_distance: f32,
+    /// How much each zoom step changes the distance, as a fraction of it
+    zoom_step: f32,
+    edge_margin: f32,
+    /// The ground point under the cursor when the drag started
+    grabbed: Option<Point3<f32>>,
+}
+
+impl CameraSystem {
+    pub fn new(cfg: &ClientConfig) -> CameraSystem {
+        CameraSystem {
+            pan_speed: cfg.camera_pan_speed/1000.0,
+            min_distance: cfg.camera_min_distance,
+            max_distance: cfg.camera_max_distance,
+            zoom_step: cfg.camera_zoom_step,
+            edge_margin: cfg.edge_scroll_margin as f32,
+            grabbed: None,
+        }
+    }
+
+    /// Which way the edges of the screen the cursor is against push the view, -1 to 1 on each
+    /// axis with positive y up the screen.
+    fn edge_scroll(&self, cursor: Vector2<f32>, screen: Vector2<f32>) -> Vector2<f32> {
+        let mut push: Vector2<f32> = nalgebra::zero();
+        if cursor.x < self.edge_margin {
+            push.x = -1.0;
+        }
+        else if cursor.x > screen.x - self.edge_margin {
+            push.x = 1.0;
+        }
+        if cursor.y < self.edge_margin {
+            push.y = 1.0;
+        }
+        else if cursor.y > screen.y - self.edge_margin {
+            push.y = -1.0;
+        }
+        push
+    }
+}
+
+/// World directions along the ground for right and up on screen.
+fn screen_axes(camera: &Camera) -> (Vector3<f32>, Vector3<f32>) {
+    let mut forward = camera.target - camera.position;
+    forward.z = 0.0;
+    // looking straight down, the top of the screen is +y
+    let up = if forward.norm() > 1.0e-4 { forward.normalize() } else { Vector3::new(0.0, 1.0, 0.0) };
+    let right = Vector3::new(up.y, -up.x, 0.0);
+    (right, up)
+}
+
+impl System<Message, ClientSystemContext> for CameraSystem {
+    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, ctx: ClientSystemContext) {
+        let (mut camera, mut control, cursor, selection, movement) = arg.fetch(|w| {
+            (
+                w.write_resource::<Camera>(),
+                w.write_resource::<CameraControl>(),
+                w.read_resource::<CursorPosition>(),
+                w.read_resource::<CurrentSelection>(),
+                w.read::<Movement>(),
+            )
+        });
+        let dt = ctx.dt.num_milliseconds() as f32;
+
+        // zoom first, so the rest works at the new distance
+        if control.zoom != 0.0 {
+            let distance = camera.distance()*(1.0 - self.zoom_step).powf(control.zoom);
+            camera.set_distance(distance.max(self.min_distance).min(self.max_distance));
+            control.zoom = 0.0;
+        }
+
+        // keep whatever was grabbed under the cursor
+        if control.dragging {
+            match (self.grabbed, camera.ground_at(cursor.0)) {
+                (Some(grabbed), Some(under)) => {
+                    camera.translate(grabbed - under);
+                    control.follow = false;
+                },
+                (None, under) => self.grabbed = under,
+                _ => (),
+            }
+        }
+        else {
+            self.grabbed = None;
+        }
+
+        let mut pan = control.pan;
+        if control.focused && !control.dragging {
+            let cursor = Vector2::new(cursor.0.x as f32, cursor.0.y as f32);
+            pan = pan + self.edge_scroll(cursor, camera.screen_size());
+        }
+        if pan.x != 0.0 || pan.y != 0.0 {
+            let (right, up) = screen_axes(&camera);
+            let speed = self.pan_speed*camera.distance()*dt;
+            let offset = (right*pan.x + up*pan.y)*speed;
+            camera.translate(offset);
+            control.follow = false;
+        }
+
+        if control.follow {
+            let positions: Vec<Point3<f32>> = selection.0.iter().filter_map(|&e| movement.get(e)).map(|m| m.position).collect();
+            if !positions.is_empty() {
+                let sum = positions.iter().fold(Point3::new(0.0, 0.0, 0.0), |sum, p| sum + p.to_vector());
+                camera.center_on(sum/positions.len() as f32);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, _: &mut World, _: &Message) { }
+}
@@ -1,78 +1,333 @@
-use specs::{World, MessageQueue};
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+
+use specs::{Entity, World, MessageQueue};
+
+use time::{Duration, PreciseTime};
 
 use glium;
 use glium::Display;
 
 use client::ClientSystemContext;
+use client::bindings::{parse_binding, Action, Bindings, Input, Keybinds, Modifiers, Trigger};
 
-use common::Message;
-use common::resources::{CursorPosition, CurrentHover, CurrentSelection, IsRunning};
+use nalgebra::{Point2, Point3, Vector2};
 
-pub enum Keybinds {
-    InGame,
-    Menu,
-}
+use common::{Message, SelectionMode};
+use common::components::Movement;
+use common::resources::{Camera, CameraControl, ControlGroups, CursorPosition, CurrentHover, CurrentSelection, DragSelection, IsRunning, ReplicatedEntities};
+
+/// How far the cursor has to move with the button held before a click becomes a drag, in pixels
+const DRAG_THRESHOLD: i32 = 4;
+
+/// Pressing a control group's key twice within this many ms centers the camera on it
+const DOUBLE_TAP_MS: i64 = 300;
+
+/// Touchpads scroll in pixels rather than lines. This many make up one step of the wheel.
+const PIXELS_PER_WHEEL_STEP: f32 = 40.0;
 
 pub struct InputSystem {
     current_keybinds: Keybinds,
+    bindings: Bindings,
+    /// Where changes to the bindings are saved, if anywhere
+    bindings_path: Option<PathBuf>,
+    /// Text typed so far while chat entry is open
+    chat_input: String,
+    modifiers: Modifiers,
+    /// While a select action's input is held: what it was, where it started and how the
+    /// picked entities combine with the selection
+    drag_start: Option<(Trigger, Point2<i32>, SelectionMode)>,
+    /// The control group last recalled, and when
+    last_recall: Option<(u8, PreciseTime)>,
+    /// Camera actions that last as long as their input is held
+    held: HashMap<Trigger, Action>,
+    /// Whether the window has focus. glutin only tells us when this changes, and windows start
+    /// out focused.
+    window_focused: bool,
+    /// Pixels scrolled that don't add up to a whole wheel step yet, positive is up
+    scrolled_pixels: f32,
 }
 
 use glium::glutin::Event;
 use glium::glutin::VirtualKeyCode as KC;
 impl InputSystem {
-    pub fn new() -> InputSystem {
+    pub fn new(bindings: Bindings, bindings_path: Option<PathBuf>) -> InputSystem {
         InputSystem {
             current_keybinds: Keybinds::InGame,
+            bindings: bindings,
+            bindings_path: bindings_path,
+            chat_input: String::new(),
+            modifiers: Modifiers::none(),
+            drag_start: None,
+            last_recall: None,
+            held: HashMap::new(),
+            window_focused: true,
+            scrolled_pixels: 0.0,
         }
     }
 
-    pub fn run(&mut self, window: &mut Display, world: &mut World, msg: MessageQueue<Message>, ctx: ClientSystemContext) {
+    /// Make `input` the only way to do `action` in `context`, saving the change if the bindings
+    /// came from a file.
+    pub fn rebind(&mut self, context: Keybinds, action: Action, input: Input) {
+        self.bindings.rebind(context, action, input);
+        if let Some(ref path) = self.bindings_path {
+            if let Err(error) = self.bindings.save(path) {
+                println!("failed to save bindings to {:?}: {:?}", path, error);
+            }
+        }
+    }
+
+    pub fn run(&mut self, window: &mut Display, world: &mut World, msg: MessageQueue<Message>, _: ClientSystemContext) {
+        use glium::glutin::{ElementState, MouseScrollDelta};
         for event in window.poll_events() {
             match event {
-                Event::MouseMoved(x, y) => {let mut pos = world.write_resource::<CursorPosition>(); pos.0.x = x; pos.0.y = y;},
+                Event::Closed => {
+                    msg.send(Message::Quit);
+                    world.write_resource::<IsRunning>().0 = false;
+                },
+                Event::MouseMoved(x, y) => {
+                    world.write_resource::<CursorPosition>().0 = Point2::new(x, y);
+                    self.update_focus(world);
+                    self.dragged(Point2::new(x, y), world);
+                },
+                Event::ReceivedCharacter(c) => {
+                    if self.current_keybinds == Keybinds::Menu && !c.is_control() {
+                        self.chat_input.push(c);
+                    }
+                },
+                Event::KeyboardInput(state, _, Some(key)) => {
+                    let pressed = state == ElementState::Pressed;
+                    match key {
+                        KC::LShift | KC::RShift => self.modifiers.shift = pressed,
+                        KC::LControl | KC::RControl => self.modifiers.ctrl = pressed,
+                        KC::LAlt | KC::RAlt => self.modifiers.alt = pressed,
+                        _ => (),
+                    }
+                    self.input(Trigger::Key(key), pressed, world, &msg);
+                },
+                Event::MouseInput(state, button) => {
+                    self.input(Trigger::Mouse(button), state == ElementState::Pressed, world, &msg);
+                },
+                Event::MouseWheel(MouseScrollDelta::LineDelta(_, y), _) => {
+                    if y != 0.0 {
+                        self.input(if y > 0.0 { Trigger::WheelUp } else { Trigger::WheelDown }, true, world, &msg);
+                    }
+                },
+                Event::MouseWheel(MouseScrollDelta::PixelDelta(_, y), _) => {
+                    self.scrolled_pixels += y;
+                    while self.scrolled_pixels.abs() >= PIXELS_PER_WHEEL_STEP {
+                        let trigger = if self.scrolled_pixels > 0.0 { Trigger::WheelUp } else { Trigger::WheelDown };
+                        self.scrolled_pixels -= PIXELS_PER_WHEEL_STEP*self.scrolled_pixels.signum();
+                        self.input(trigger, true, world, &msg);
+                    }
+                },
+                Event::Resized(width, height) => {
+                    // minimizing shrinks the window to nothing on some platforms
+                    if width > 0 && height > 0 {
+                        world.write_resource::<Camera>().set_screen_size(width, height);
+                        self.update_focus(world);
+                    }
+                },
+                Event::Focused(focused) => {
+                    self.window_focused = focused;
+                    self.update_focus(world);
+                    if !focused {
+                        // we won't hear about anything let go of while we're in the background
+                        self.held.clear();
+                    }
+                },
+                _ => (),
+            }
+        }
+        self.steer_camera(world);
+    }
+
+    /// Edge scrolling only happens while the window has focus and the cursor is in it. glutin
+    /// doesn't say when the cursor leaves the window, so this goes by where it last moved to.
+    fn update_focus(&self, world: &mut World) {
+        let cursor = world.read_resource::<CursorPosition>().0;
+        let screen = world.read_resource::<Camera>().screen_size();
+        let inside = cursor.x >= 0 && cursor.y >= 0 && (cursor.x as f32) < screen.x && (cursor.y as f32) < screen.y;
+        world.write_resource::<CameraControl>().focused = self.window_focused && inside;
+    }
+
+    /// Tell the camera which way to go from the camera actions held.
+    fn steer_camera(&self, world: &mut World) {
+        let mut control = world.write_resource::<CameraControl>();
+        let mut pan: Vector2<f32> = Vector2::new(0.0, 0.0);
+        let mut dragging = false;
+        for action in self.held.values() {
+            match *action {
+                Action::PanUp => pan.y = 1.0,
+                Action::PanDown => pan.y = -1.0,
+                Action::PanLeft => pan.x = -1.0,
+                Action::PanRight => pan.x = 1.0,
+                Action::DragPan => dragging = true,
                 _ => (),
             }
+        }
+        control.pan = pan;
+        control.dragging = dragging;
+    }
+
+    /// Do whatever `trigger` is bound to with the modifiers currently held.
+    fn input(&mut self, trigger: Trigger, pressed: bool, world: &mut World, msg: &MessageQueue<Message>) {
+        if !pressed {
+            self.held.remove(&trigger);
+            self.released(trigger, world, msg);
+            return;
+        }
+        let input = Input::new(trigger, self.modifiers);
+        if let Some(action) = self.bindings.action(self.current_keybinds, &input) {
             match self.current_keybinds {
-                Keybinds::InGame => self.handle_ingame(event, world, &msg, &ctx),
-                Keybinds::Menu => () // TODO,
+                Keybinds::InGame => self.ingame_action(action, trigger, world, msg),
+                Keybinds::Menu => self.menu_action(action, msg),
+            }
+        }
+    }
+
+    /// Once the cursor has moved far enough with a select input held, it's a drag.
+    fn dragged(&mut self, cursor: Point2<i32>, world: &mut World) {
+        if let Some((_, start, _)) = self.drag_start {
+            let mut drag = world.write_resource::<DragSelection>();
+            if drag.0.is_some() || (cursor.x - start.x).abs() > DRAG_THRESHOLD || (cursor.y - start.y).abs() > DRAG_THRESHOLD {
+                drag.0 = Some((start, cursor));
             }
         }
     }
 
-    pub fn handle_ingame(&mut self, event: Event, world: &mut World, msg: &MessageQueue<Message>, _: &ClientSystemContext) {
-        use glium::glutin::{ElementState, MouseButton};
-        match event {
-            Event::Closed => {
+    /// Letting go of whatever started a selection finishes it: a click picks what's under the
+    /// cursor, a drag everything in the box.
+    fn released(&mut self, trigger: Trigger, world: &mut World, msg: &MessageQueue<Message>) {
+        let mode = match self.drag_start {
+            Some((held, _, mode)) if held == trigger => mode,
+            _ => return,
+        };
+        self.drag_start = None;
+        match world.write_resource::<DragSelection>().0.take() {
+            Some((start, end)) => msg.send(Message::SelectArea(start, end, mode)),
+            None => msg.send(Message::SelectEntity(mode)),
+        }
+    }
+
+    /// Select a control group. Recalling the same group twice in a row centers the camera on it
+    /// too, including members that are out of view.
+    fn control_group(&mut self, number: u8, mode: SelectionMode, world: &mut World, msg: &MessageQueue<Message>) {
+        let members: Vec<Entity> = match world.read_resource::<ControlGroups>().groups.get(&number) {
+            Some(group) => group.iter().cloned().collect(),
+            None => return,
+        };
+
+        let now = PreciseTime::now();
+        let double_tap = match self.last_recall {
+            Some((last, at)) => last == number && at.to(now) < Duration::milliseconds(DOUBLE_TAP_MS),
+            None => false,
+        };
+        self.last_recall = Some((number, now));
+
+        // only the members in view can be selected
+        let (group, center) = {
+            let groups = world.read_resource::<ControlGroups>();
+            let replicated = world.read_resource::<ReplicatedEntities>();
+            let movement = world.read::<Movement>();
+            let local = |e: &Entity| replicated.to_local.get(e).cloned();
+            let group: Vec<Entity> = members.iter().filter_map(&local).collect();
+            let positions: Vec<Point3<f32>> = members.iter().filter_map(|e| {
+                local(e).and_then(|l| movement.get(l)).map(|m| m.position).or_else(|| groups.last_seen.get(e).cloned())
+            }).collect();
+            let center = if positions.is_empty() {
+                None
+            }
+            else {
+                let sum = positions.iter().fold(Point3::new(0.0, 0.0, 0.0), |sum, p| sum + p.to_vector());
+                Some(sum/positions.len() as f32)
+            };
+            (group, center)
+        };
+        if double_tap {
+            if let Some(center) = center {
+                world.write_resource::<Camera>().center_on(center);
+            }
+        }
+
+        msg.send(Message::SelectEntities(group, mode));
+    }
+
+    fn ingame_action(&mut self, action: Action, trigger: Trigger, world: &mut World, msg: &MessageQueue<Message>) {
+        match action {
+            Action::Quit => {
                 msg.send(Message::Quit);
                 world.write_resource::<IsRunning>().0 = false;
             },
-            Event::KeyboardInput(state, _, key) => {
-                if state == glium::glutin::ElementState::Pressed {
-                    match key.unwrap() {
-                        KC::Escape => { 
-                            msg.send(Message::Quit);
-                            world.write_resource::<IsRunning>().0 = false;
-                        },
-                        _ => ()
-                    }
+            Action::OpenChat => {
+                self.chat_input.clear();
+                self.current_keybinds = Keybinds::Menu;
+            },
+            Action::Select | Action::AddToSelection | Action::ToggleSelection => {
+                let mode = match action {
+                    Action::AddToSelection => SelectionMode::Add,
+                    Action::ToggleSelection => SelectionMode::Toggle,
+                    _ => SelectionMode::Replace,
+                };
+                self.drag_start = Some((trigger, world.read_resource::<CursorPosition>().0, mode));
+            },
+            Action::Command | Action::QueueCommand => {
+                let selection: Vec<_> = world.read_resource::<CurrentSelection>().0.iter().cloned().collect();
+                if !selection.is_empty() {
+                    let hover = world.read_resource::<CurrentHover>().clone();
+                    // the server knows who we are, so the client id is left for it to fill in
+                    msg.send(Message::CommandGroup(0, selection, hover, action == Action::QueueCommand));
                 }
-            }
+            },
+            Action::RecallGroup(number) => self.control_group(number, SelectionMode::Replace, world, msg),
+            Action::AddGroup(number) => self.control_group(number, SelectionMode::Add, world, msg),
+            Action::StoreGroup(number) => {
+                let selection = {
+                    let replicated = world.read_resource::<ReplicatedEntities>();
+                    world.read_resource::<CurrentSelection>().0.iter().filter_map(|e| replicated.to_server.get(e).cloned()).collect()
+                };
+                world.write_resource::<ControlGroups>().store(number, selection);
+            },
+            Action::PanUp | Action::PanDown | Action::PanLeft | Action::PanRight | Action::DragPan => {
+                self.held.insert(trigger, action);
+            },
+            Action::ZoomIn => world.write_resource::<CameraControl>().zoom += 1.0,
+            Action::ZoomOut => world.write_resource::<CameraControl>().zoom -= 1.0,
+            Action::ToggleFollow => {
+                let mut control = world.write_resource::<CameraControl>();
+                control.follow = !control.follow;
+            },
+            _ => (),
+        }
+    }
 
-            Event::MouseInput(state, button) => {
-                // TODO instead of MouseButton::Left, we should have a mapping for "move" and
-                // "action", etc.
-                if state == ElementState::Pressed {
-                    if button == MouseButton::Left {
-                        msg.send(Message::SelectEntity);
-                    }
-                    else if button == MouseButton::Right {
-                        world.read_resource::<CurrentSelection>().0.map(|sel_entity| {
-                            msg.send(Message::InteractWith(sel_entity, world.read_resource::<CurrentHover>().clone()));
-                        });
+    /// Chat text entry. Keys typed here go into the chat message instead of triggering game
+    /// actions, apart from the ones bound in the menu bindings.
+    fn menu_action(&mut self, action: Action, msg: &MessageQueue<Message>) {
+        match action {
+            Action::SendChat => {
+                let text = mem::replace(&mut self.chat_input, String::new());
+                if text.starts_with("/bind ") {
+                    // /bind context action input
+                    match parse_binding(&text["/bind ".len()..]) {
+                        Ok((context, action, input)) => self.rebind(context, action, input),
+                        Err(error) => println!("can't bind that: {}", error),
                     }
                 }
-            }
-            _ => ()
+                else if !text.trim().is_empty() {
+                    msg.send(Message::SendChat(text));
+                }
+                self.current_keybinds = Keybinds::InGame;
+            },
+            Action::CancelChat => {
+                self.chat_input.clear();
+                self.current_keybinds = Keybinds::InGame;
+            },
+            Action::DeleteChar => {
+                self.chat_input.pop();
+            },
+            _ => (),
         }
     }
 }
@@ -3,9 +3,13 @@ mod input;
 mod movement;
 mod selection;
 mod network;
+mod replication;
+mod camera;
 
 pub use self::render::*;
 pub use self::input::*;
 pub use self::movement::*;
 pub use self::selection::*;
 pub use self::network::*;
+pub use self::replication::*;
+pub use self::camera::*;
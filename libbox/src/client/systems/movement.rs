@@ -1,15 +1,11 @@
-use time::Duration;
-
 use specs::{Join, MessageQueue, RunArg, System, World};
 
 use client::ClientSystemContext;
 
 use common::Message;
-use common::components::{Controllable, Movement};
+use common::components::{Controllable, Movement, MovementStats};
 use common::resources::CurrentHover;
 
-use nalgebra::Point3;
-
 
 pub struct MovementSystem { }
 
@@ -17,35 +13,17 @@ impl MovementSystem {
     pub fn new() -> MovementSystem {
         MovementSystem { }
     }
-
-    fn do_movement(&mut self, m: &mut Movement, timestep: Duration) {
-        let (begin, end, mut t) = m.current_path.unwrap();
-
-        let progress = m.speed*(timestep.num_milliseconds() as f32);
-        t += progress;
-
-        if t >= 1.0 {
-            m.position = end;
-            m.current_path = None;
-        }
-        else {
-            let new_position = lerpf32(&begin, &end, &t);
-            m.position = new_position;
-            m.current_path = Some((begin, end, t));
-        }
-    }
 }
 
 impl System<Message, ClientSystemContext> for MovementSystem {
     fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, ctx: ClientSystemContext) {
-        let mut mvt = arg.fetch(|w| w.write::<Movement>());
+        let (entities, mut mvt, stats) = arg.fetch(|w| (w.entities(), w.write::<Movement>(), w.read::<MovementStats>()));
 
+        let default_stats = MovementStats::default();
         let mut accum = ctx.dt;
         while accum > ctx.timestep {
-            for m in (&mut mvt).iter() {
-                if m.current_path.is_some() {
-                    self.do_movement(m, ctx.timestep);
-                }
+            for (e, m) in (&entities, &mut mvt).iter() {
+                m.advance(stats.get(e).unwrap_or(&default_stats), ctx.timestep.num_milliseconds() as f32);
             }
             accum = accum - ctx.timestep;
         }
@@ -53,7 +31,7 @@ impl System<Message, ClientSystemContext> for MovementSystem {
 
     fn handle_message(&mut self, world: &mut World, msg: &Message) {
         match *msg {
-            Message::InteractWith(e, ref interact) => {
+            Message::InteractWith(_, e, ref interact) => {
                 let control = world.read::<Controllable>();
                 let mut movement = world.write::<Movement>();
                 control.get(e)
@@ -63,15 +41,28 @@ impl System<Message, ClientSystemContext> for MovementSystem {
                     _ => (),
                 });
             },
+            Message::QueueInteraction(_, e, ref interact) => {
+                let control = world.read::<Controllable>();
+                let mut movement = world.write::<Movement>();
+                control.get(e)
+                .and_then(|_| movement.get_mut(e))
+                .map(|m| match *interact {
+                    CurrentHover::Ground(target) => m.queue_target(target),
+                    _ => (),
+                });
+            },
+            Message::CommandGroup(_, ref group, CurrentHover::Ground(target), queue) => {
+                // the server decides where each of them ends up, head for the target until it
+                // tells us
+                let control = world.read::<Controllable>();
+                let mut movement = world.write::<Movement>();
+                for &e in group {
+                    control.get(e)
+                    .and_then(|_| movement.get_mut(e))
+                    .map(|m| if queue { m.queue_target(target) } else { m.set_target(target) });
+                }
+            },
             _ => (),
         }
     }
 }
-
-pub fn lerpf32(begin: &Point3<f32>, end: &Point3<f32>, t: &f32) -> Point3<f32> {
-    let x = (1.0 - t)*begin.x + t*end.x;
-    let y = (1.0 - t)*begin.y + t*end.y;
-    let z = (1.0 - t)*begin.z + t*end.z;
-
-    Point3::new(x, y, z)
-}
@@ -1,14 +1,14 @@
-use std::io::{self, Read, Write};
+use std::io;
 
 use std::net::TcpStream;
 
-use rustc_serialize::json;
-
-use specs::{MessageQueue, RunArg, System, World};
+use specs::{Entity, MessageQueue, RunArg, System, World};
 
 use client::{ClientConfig, ClientSystemContext};
 
-use common::{Message, NetworkMessage, Version};
+use common::{Capabilities, ConnectRequest, Message, NetworkMessage, ViewArea};
+use common::net::{ClientEncryption, Connection, Heartbeat};
+use common::resources::{Camera, ChatLog, CurrentHover, Latency, ReplicatedEntities};
 
 #[derive(Copy, Clone, Debug)]
 enum ConnectionState {
@@ -18,114 +18,225 @@ enum ConnectionState {
 }
 
 struct ServerConnection {
-    pub stream: Option<TcpStream>,
+    pub connection: Option<Connection>,
     pub connection_state: ConnectionState,
+    /// Negotiated during the handshake
+    pub protocol: u32,
+    pub capabilities: Capabilities,
+    pub heartbeat: Heartbeat,
 }
 
 impl ServerConnection {
-    pub fn new(connect: io::Result<TcpStream>) -> ServerConnection {
-        let stream: Option<TcpStream>;
+    pub fn new(connect: io::Result<TcpStream>, encryption: ClientEncryption, heartbeat: Heartbeat) -> ServerConnection {
+        let connection = connect.and_then(|s| {
+            s.set_nodelay(true).unwrap();
+            s.set_nonblocking(true).unwrap();
+            match encryption {
+                ClientEncryption::Plaintext => Ok(Connection::plaintext(s)),
+                ClientEncryption::Noise(server_key) => Connection::noise_initiator(s, server_key),
+            }
+        });
+
+        let connection: Option<Connection>;
         let state: ConnectionState;
-        match connect {
-            Ok(s) => {
-                s.set_nodelay(true).unwrap();
-                s.set_nonblocking(true).unwrap();
-                stream = Some(s); 
+        match connection {
+            Ok(c) => {
+                connection = Some(c); 
                 state = ConnectionState::Connecting;
             },
             Err(error) => {
                 println!("Connecting to server failed, {:?}", error);
-                stream = None;
+                connection = None;
                 state = ConnectionState::Disconnected;
             }
         }
 
         ServerConnection {
-            stream: stream,
+            connection: connection,
             connection_state: state,
+            protocol: 0,
+            capabilities: Capabilities::empty(),
+            heartbeat: heartbeat,
         }
     }
+
+    pub fn close(&mut self) {
+        self.connection_state = ConnectionState::Disconnected;
+        self.connection = None;
+    }
 }
 
 pub struct NetworkSystem {
     current_server: ServerConnection,
+    /// The view area the server was last told about
+    last_view: Option<ViewArea>,
 }
 
 impl NetworkSystem {
     pub fn new(cfg: ClientConfig) -> NetworkSystem {
         let connection = TcpStream::connect(cfg.server_address);
 
-        let server_connection = ServerConnection::new(connection); 
+        let heartbeat = Heartbeat::new(cfg.heartbeat_interval, cfg.idle_timeout);
+        let server_connection = ServerConnection::new(connection, cfg.encryption, heartbeat); 
 
         let mut sys = NetworkSystem {
             current_server: server_connection,
+            last_view: None,
         };
-        let version = env!("CARGO_PKG_VERSION").to_owned();
-        sys.send_connect(version);
+        sys.send_connect(ConnectRequest::new(cfg.player_name, cfg.auth_token));
         sys
     }
 
-    pub fn send_connect(&mut self, version: String) {
+    pub fn send_connect(&mut self, request: ConnectRequest) {
         // TODO: don't call unwrap, actually handle connection errors, close, etc
-        let stream = self.current_server.stream.as_mut().unwrap();
+        let connection = match self.current_server.connection {
+            Some(ref mut c) => c,
+            None => return,
+        };
 
-        let connect = NetworkMessage::Connect(Version(version));
-        let connect_encoded = json::encode(&connect).unwrap();
+        let connect = NetworkMessage::Connect(request);
+        connection.send(&connect).unwrap();
+    }
+
+    /// Ping the server if it's time, and give up on it if it has gone quiet.
+    fn handle_heartbeat(&mut self) {
+        let heartbeats = self.current_server.capabilities.contains(Capabilities::HEARTBEAT);
+        let connecting = match self.current_server.connection_state {
+            ConnectionState::Connecting => true,
+            _ => false,
+        };
+        if !(heartbeats || connecting) || self.current_server.connection.is_none() {
+            return;
+        }
+
+        if self.current_server.heartbeat.timed_out() {
+            println!("Server timed out");
+            self.current_server.close();
+            return;
+        }
 
-        stream.write(connect_encoded.as_bytes()).unwrap();
+        if heartbeats {
+            if let Some(ping) = self.current_server.heartbeat.poll() {
+                let _ = self.current_server.connection.as_mut().unwrap().send(&ping);
+            }
+        }
     }
 
-    pub fn handle_server_message(&mut self, msgq: MessageQueue<Message>, connecting: bool) {
-        // TODO: don't call unwrap, actually handle connection errors, close, etc
-        let stream = self.current_server.stream.as_mut().unwrap();
+    /// Push out whatever has been queued for the server since the last tick.
+    fn flush(&mut self) {
+        let result = match self.current_server.connection {
+            Some(ref mut connection) => connection.flush(),
+            None => return,
+        };
+        if let Err(error) = result {
+            println!("Lost connection to server: {:?}", error);
+            self.current_server.close();
+        }
+    }
 
-        let mut buf = String::new();
-        match stream.read_to_string(&mut buf) {
-            Ok(_) => (),
-            Err(error) => {
-                // apparently with nonblocking sockets, it returns WouldBlock when the read is
-                // successful?
-                if error.kind() == io::ErrorKind::WouldBlock ||
-                   error.kind() == io::ErrorKind::TimedOut {}
-                else {
-                    // actual error
-                    println!("{:?}", error);
-                    return;
-                }
-            },
+    /// Pass a command for the player's entities on to the server.
+    fn send_command(&mut self, command: Message) {
+        if let ConnectionState::Connected = self.current_server.connection_state {
+            let command = NetworkMessage::GameMessage(command);
+            if let Err(error) = self.current_server.connection.as_mut().unwrap().send(&command) {
+                println!("Lost connection to server: {:?}", error);
+                self.current_server.close();
+            }
         }
+    }
 
-        if buf.len() < 1 {
+    /// Tell the server what we're looking at, if it has changed.
+    fn send_view(&mut self, camera: &Camera) {
+        if !self.current_server.capabilities.contains(Capabilities::REPLICATION) {
             return;
         }
+        let view = camera.view_area();
+        if self.last_view == Some(view) {
+            return;
+        }
+
+        let result = self.current_server.connection.as_mut().unwrap().send(&NetworkMessage::View(view));
+        match result {
+            Ok(()) => self.last_view = Some(view),
+            Err(error) => {
+                println!("Lost connection to server: {:?}", error);
+                self.current_server.close();
+            }
+        }
+    }
 
-        let msg: NetworkMessage;
-        match json::decode(&buf) {
-            Ok(m) => {msg = m},
+    pub fn handle_server_message(&mut self, msgq: MessageQueue<Message>, chat_log: &mut ChatLog) {
+        let result = self.current_server.connection.as_mut().unwrap().receive();
+        match result {
+            Ok(0) => (),
+            Ok(_) => self.current_server.heartbeat.received(),
             Err(error) => {
-                println!("error decoding message: {:?}", error);
-                println!("{}", buf);
+                println!("Lost connection to server: {:?}", error);
+                self.current_server.close();
                 return;
             }
         }
 
-        use common::NetworkMessage::*;
-        match msg {
-            GameMessage(message) => {
-                // ignore messages while we're still connecting
-                if connecting {
+        while let Some(msg) = self.current_server.connection.as_mut().and_then(|c| c.next_message()) {
+            let msg = match msg {
+                Ok(m) => m,
+                Err(error) => {
+                    println!("error decoding message: {:?}", error);
+                    continue;
+                }
+            };
+
+            use common::NetworkMessage::*;
+            match msg {
+                GameMessage(message) => {
+                    // ignore messages while we're still connecting
+                    if let ConnectionState::Connecting = self.current_server.connection_state {
+                        continue;
+                    }
+                    msgq.send(message);
+                },
+                Connect(_) | ServerInfoRequest | SendChat(_) | View(_) | AckSnapshot(_) => (), // only used by server
+                ServerInfoResponse(_) => (), // only used by query_server_info
+                Accepted(welcome) => {
+                    println!("Connected to server version {} using protocol {}", welcome.version.0, welcome.protocol);
+                    self.current_server.protocol = welcome.protocol;
+                    self.current_server.capabilities = welcome.capabilities;
+                    self.current_server.connection_state = ConnectionState::Connected;
+                    self.last_view = None;
+                },
+                Rejected(reason) => {
+                    println!("Server refused connection: {:?}", reason);
+                    self.current_server.close();
+                    return;
+                },
+                Motd(motd) => {
+                    println!("Message of the day: {}", motd); 
+                },
+                Ping(sequence) => {
+                    let pong = Pong(sequence);
+                    let _ = self.current_server.connection.as_mut().unwrap().send(&pong);
+                },
+                Pong(sequence) => self.current_server.heartbeat.pong(sequence),
+                Replicate(snapshot) => {
+                    if let ConnectionState::Connected = self.current_server.connection_state {
+                        // applied by the ReplicationSystem as soon as this dispatch finishes
+                        msgq.send(Message::Replicate(snapshot.changes));
+                        let ack = AckSnapshot(snapshot.sequence);
+                        let _ = self.current_server.connection.as_mut().unwrap().send(&ack);
+                    }
+                },
+                Chat(message) => {
+                    match message.from {
+                        Some(ref name) => println!("<{}> {}", name, message.text),
+                        None => println!("* {}", message.text),
+                    }
+                    chat_log.push(message);
+                },
+                Disconnect(reason) => {
+                    println!("Disconnected from server: {}", reason.0);
+                    self.current_server.close();
                     return;
                 }
-                msgq.send(message);
-            },
-            Connect(_) => (), // only used by server
-            Motd(motd) => {
-                println!("Connected to server");
-                println!("Message of the day: {}", motd); 
-                self.current_server.connection_state = ConnectionState::Connected;
-            },
-            Disconnect(_) => {
-                // close connection
             }
         }
     }
@@ -133,23 +244,73 @@ impl NetworkSystem {
 
 impl System<Message, ClientSystemContext> for NetworkSystem {
     fn run(&mut self, arg: RunArg, msg: MessageQueue<Message>, _: ClientSystemContext) {
-        let _ = arg.fetch(|_| {});
+        let (mut chat_log, mut latency, camera) = arg.fetch(|w| {
+            (w.write_resource::<ChatLog>(), w.write_resource::<Latency>(), w.read_resource::<Camera>())
+        });
 
         let state = self.current_server.connection_state;
         match state {
-            ConnectionState::Connected => {
-                self.handle_server_message(msg, false);
-            },
-            ConnectionState::Connecting => {
-                self.handle_server_message(msg, true);
+            ConnectionState::Connected | ConnectionState::Connecting => {
+                self.handle_server_message(msg, &mut chat_log);
+                self.handle_heartbeat();
+                if let ConnectionState::Connected = self.current_server.connection_state {
+                    self.send_view(&camera);
+                }
+                self.flush();
             },
             ConnectionState::Disconnected => (),
         }
+        latency.0 = self.current_server.heartbeat.rtt();
     }
 
-    fn handle_message(&mut self, _: &mut World, msg: &Message) {
+    fn handle_message(&mut self, world: &mut World, msg: &Message) {
         match *msg {
+            Message::InteractWith(client_id, e, ref hover) => {
+                if let Some((entity, hover)) = to_server(world, e, hover) {
+                    self.send_command(Message::InteractWith(client_id, entity, hover));
+                }
+            },
+            Message::QueueInteraction(client_id, e, ref hover) => {
+                if let Some((entity, hover)) = to_server(world, e, hover) {
+                    self.send_command(Message::QueueInteraction(client_id, entity, hover));
+                }
+            },
+            Message::CommandGroup(client_id, ref group, ref hover, queue) => {
+                let translated: Vec<(Entity, CurrentHover)> = group.iter().filter_map(|&e| to_server(world, e, hover)).collect();
+                if let Some(hover) = translated.first().map(|&(_, ref hover)| hover.clone()) {
+                    let entities = translated.into_iter().map(|(e, _)| e).collect();
+                    self.send_command(Message::CommandGroup(client_id, entities, hover, queue));
+                }
+            },
+            Message::SendChat(ref text) => {
+                if !self.current_server.capabilities.contains(Capabilities::CHAT) {
+                    println!("This server does not support chat");
+                    return;
+                }
+                if let ConnectionState::Connected = self.current_server.connection_state {
+                    let chat = NetworkMessage::SendChat(text.clone());
+                    if let Err(error) = self.current_server.connection.as_mut().unwrap().send(&chat) {
+                        println!("Lost connection to server: {:?}", error);
+                        self.current_server.close();
+                    }
+                }
+            },
             _ => (),
         }
     }
 }
+
+/// The server knows entities by its own ids. Returns None if the entity isn't one the server
+/// sent us.
+fn to_server(world: &World, e: Entity, hover: &CurrentHover) -> Option<(Entity, CurrentHover)> {
+    let replicated = world.read_resource::<ReplicatedEntities>();
+    let entity = match replicated.to_server.get(&e) {
+        Some(&entity) => entity,
+        None => return None,
+    };
+    let hover = match *hover {
+        CurrentHover::Entity(h) => replicated.to_server.get(&h).map_or(CurrentHover::None, |&h| CurrentHover::Entity(h)),
+        ref other => other.clone(),
+    };
+    Some((entity, hover))
+}
@@ -3,13 +3,13 @@ use specs::{Join, MessageQueue, World};
 use glium;
 use glium::{Display, DisplayBuild, Frame, Surface};
 
-use nalgebra::{Vector3};
+use nalgebra::{Eye, Matrix4, Point2, Vector3};
 
 use client::ClientSystemContext;
 use client::ClientConfig;
 
 use common::Message;
-use common::resources::Camera;
+use common::resources::{Camera, DragSelection};
 use common::components::{Movement, Render, Selection};
 
 
@@ -24,6 +24,7 @@ struct BoxRenderer {
     box_vb: glium::vertex::VertexBuffer<Vert>,
     box_shader: glium::program::Program,
     box_drawparams: glium::DrawParameters<'static>,
+    outline_vb: glium::vertex::VertexBuffer<Vert>,
 }
 
 impl BoxRenderer {
@@ -43,6 +44,8 @@ impl BoxRenderer {
 			Vert { position: br },
 		];
         let box_vb = glium::vertex::VertexBuffer::new(window, data).unwrap();
+        let outline = &[Vert { position: tr }, Vert { position: tl }, Vert { position: bl }, Vert { position: br }];
+        let outline_vb = glium::vertex::VertexBuffer::new(window, outline).unwrap();
 
         let v_shader = "
             #version 150
@@ -83,6 +86,7 @@ impl BoxRenderer {
             box_vb: box_vb,
             box_shader: box_shader,
             box_drawparams: params,
+            outline_vb: outline_vb,
         }
 
     }
@@ -97,11 +101,56 @@ impl BoxRenderer {
             model: render.model_transform.as_ref().clone(),
             color: render.color.as_ref().clone(),
         };
-        frame.draw(&self.box_vb, &indices, &self.box_shader, &uniforms, &self.box_drawparams).unwrap();
+        let params = glium::DrawParameters {
+            viewport: Some(viewport(camera)),
+            .. self.box_drawparams.clone()
+        };
+        frame.draw(&self.box_vb, &indices, &self.box_shader, &uniforms, &params).unwrap();
+    }
+
+    /// Outline the rectangle between two corners on screen, in pixels, over everything else.
+    pub fn render_screen_rect(&self, a: Point2<i32>, b: Point2<i32>, color: Vector3<f32>, frame: &mut Frame, camera: &Camera) {
+        let size = camera.screen_size();
+        let to_ndc = |p: Point2<i32>| (2.0*p.x as f32/size.x - 1.0, 1.0 - 2.0*p.y as f32/size.y);
+        let (ax, ay) = to_ndc(a);
+        let (bx, by) = to_ndc(b);
+
+        // the outline runs from -1 to 1, so scale it to half the size and move it to the middle
+        let mut model: Matrix4<f32> = Matrix4::new_identity(4);
+        model[(0, 0)] = (bx - ax)/2.0;
+        model[(1, 1)] = (by - ay)/2.0;
+        model[(0, 3)] = (ax + bx)/2.0;
+        model[(1, 3)] = (ay + by)/2.0;
+
+        let identity: Matrix4<f32> = Matrix4::new_identity(4);
+        let indices = glium::index::NoIndices(glium::index::PrimitiveType::LineLoop);
+        let uniforms = uniform! {
+            perspective: identity.as_ref().clone(),
+            view: identity.as_ref().clone(),
+            model: model.as_ref().clone(),
+            color: color.as_ref().clone(),
+        };
+        let params = glium::DrawParameters {
+            viewport: Some(viewport(camera)),
+            .. Default::default()
+        };
+        frame.draw(&self.outline_vb, &indices, &self.box_shader, &uniforms, &params).unwrap();
     }
 
 }
 
+/// Draw to the part of the window the camera thinks it covers, so what's drawn lines up with
+/// what's picked.
+fn viewport(camera: &Camera) -> glium::Rect {
+    let size = camera.screen_size();
+    glium::Rect {
+        left: 0,
+        bottom: 0,
+        width: size.x as u32,
+        height: size.y as u32,
+    }
+}
+
 pub struct RenderSystem {
     box_renderer: BoxRenderer, 
 }
@@ -113,7 +162,7 @@ impl RenderSystem {
         }
     }
 
-    pub fn new_window(cfg: ClientConfig) -> Display {
+    pub fn new_window(cfg: &ClientConfig) -> Display {
         glium::glutin::WindowBuilder::new()
             .with_dimensions(cfg.window_width, cfg.window_height)
             .with_depth_buffer(24)
@@ -145,6 +194,10 @@ impl RenderSystem {
             self.box_renderer.render(r, &mut frame, &camera);
         }
 
+        if let Some((start, end)) = world.read_resource::<DragSelection>().0 {
+            self.box_renderer.render_screen_rect(start, end, Vector3::new(1.0, 1.0, 1.0), &mut frame, &camera);
+        }
+
         frame.finish().unwrap();
     }
 }
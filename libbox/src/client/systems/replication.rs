@@ -0,0 +1,113 @@
+use specs::{Entity, MessageQueue, RunArg, System, World};
+
+use client::ClientSystemContext;
+
+use common::Message;
+use common::components::{Controllable, Movement, MovementStats, Render, Selection};
+use common::replication::{path_from, EntityState, QuantizedPoint, Replication};
+use common::resources::{ControlGroups, CurrentHover, CurrentSelection, ReplicatedEntities};
+
+/// Mirrors the entities the server sends us into the local world.
+pub struct ReplicationSystem { }
+
+impl ReplicationSystem {
+    pub fn new() -> ReplicationSystem {
+        ReplicationSystem { }
+    }
+
+    fn local_entity(&self, world: &World, server_entity: Entity) -> Option<Entity> {
+        world.read_resource::<ReplicatedEntities>().to_local.get(&server_entity).cloned()
+    }
+
+    fn spawn(&mut self, world: &mut World, server_entity: Entity, state: &EntityState) {
+        // we may be sent a spawn again if our acknowledgement hasn't reached the server yet
+        if let Some(local) = self.local_entity(world, server_entity) {
+            world.write::<Movement>().get_mut(local).map(|m| *m = state.to_movement());
+            world.write::<MovementStats>().insert(local, state.stats);
+            return;
+        }
+
+        let builder = world.create_now().with(Render::new()).with(state.to_movement()).with(state.stats).with(Selection::new());
+        let local = if state.controllable {
+            builder.with(Controllable::new()).build()
+        }
+        else {
+            builder.build()
+        };
+        world.write_resource::<ReplicatedEntities>().insert(server_entity, local);
+        world.write_resource::<ControlGroups>().came_into_view(server_entity);
+    }
+
+    fn moved(&mut self, world: &mut World, server_entity: Entity, position: QuantizedPoint) {
+        if let Some(local) = self.local_entity(world, server_entity) {
+            world.write::<Movement>().get_mut(local).map(|m| m.position = position.to_point());
+        }
+    }
+
+    fn path_changed(&mut self, world: &mut World, server_entity: Entity, path: &[QuantizedPoint]) {
+        if let Some(local) = self.local_entity(world, server_entity) {
+            world.write::<Movement>().get_mut(local).map(|m| m.path = path_from(path));
+        }
+    }
+
+    fn stats_changed(&mut self, world: &mut World, server_entity: Entity, stats: MovementStats) {
+        if let Some(local) = self.local_entity(world, server_entity) {
+            world.write::<MovementStats>().insert(local, stats);
+        }
+    }
+
+    /// Remove our stand-in for a server entity. Control groups hold on to entities that only
+    /// left the view, and let go of `destroyed` ones.
+    fn despawn(&mut self, world: &mut World, server_entity: Entity, destroyed: bool) {
+        // control groups hold on to entities that only left the view, so this may not be in view either
+        if destroyed {
+            world.write_resource::<ControlGroups>().remove_entity(server_entity);
+        }
+        let local = match world.write_resource::<ReplicatedEntities>().remove(server_entity) {
+            Some(local) => local,
+            None => return,
+        };
+        if !destroyed {
+            if let Some(m) = world.read::<Movement>().get(local) {
+                world.write_resource::<ControlGroups>().left_view(server_entity, m.position);
+            }
+        }
+
+        // don't leave the selection or hover pointing at a dead entity
+        {
+            world.write_resource::<CurrentSelection>().0.remove(&local);
+            let mut hover = world.write_resource::<CurrentHover>();
+            if let CurrentHover::Entity(e) = *hover {
+                if e == local {
+                    *hover = CurrentHover::None;
+                }
+            }
+        }
+        world.delete_now(local);
+    }
+}
+
+impl System<Message, ClientSystemContext> for ReplicationSystem {
+    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, _: ClientSystemContext) {
+        // everything happens in handle_message, where we can create and delete entities
+        arg.fetch(|_| ());
+    }
+
+    fn handle_message(&mut self, world: &mut World, msg: &Message) {
+        match *msg {
+            Message::Replicate(ref changes) => {
+                for change in changes {
+                    match *change {
+                        Replication::Spawn(e, ref state) => self.spawn(world, e, state),
+                        Replication::Moved(e, position) => self.moved(world, e, position),
+                        Replication::PathChanged(e, ref path) => self.path_changed(world, e, path),
+                        Replication::StatsChanged(e, stats) => self.stats_changed(world, e, stats),
+                        Replication::Despawn(e) => self.despawn(world, e, false),
+                        Replication::Destroyed(e) => self.despawn(world, e, true),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
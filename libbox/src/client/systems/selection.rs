@@ -1,4 +1,4 @@
-use specs::{Join, MessageQueue, RunArg, System, World};
+use specs::{Entity, Join, MessageQueue, RunArg, System, World};
 
 use ncollide;
 use ncollide::shape::Cuboid;
@@ -9,7 +9,7 @@ use nalgebra::Vector3;
 
 use client::ClientSystemContext;
 
-use common::Message;
+use common::{Message, SelectionMode};
 use common::components::{Movement, Selection};
 use common::resources::{Camera, CurrentHover, CurrentSelection, CursorPosition};
 
@@ -73,26 +73,62 @@ impl System<Message, ClientSystemContext> for SelectionSystem {
 
     fn handle_message(&mut self, world: &mut World, msg: &Message) {
         match *msg {
-            Message::SelectEntity => {
-                let hover = world.read_resource::<CurrentHover>();
-                let mut curr_sel = world.write_resource::<CurrentSelection>();
-                let mut sel = world.write::<Selection>();
-
-                // current selection not selected anymore
-                if let Some(e) = curr_sel.0 {
-                    sel.get_mut(e).map(|s| s.selected = false);
-                }
+            Message::SelectEntity(mode) => {
+                let picked = match *world.read_resource::<CurrentHover>() {
+                    CurrentHover::Entity(e) => vec![e],
+                    _ => Vec::new(),
+                };
+                select(world, picked, mode);
+            }
+            Message::SelectArea(a, b, mode) => {
+                let (min_x, max_x) = (a.x.min(b.x) as f32, a.x.max(b.x) as f32);
+                let (min_y, max_y) = (a.y.min(b.y) as f32, a.y.max(b.y) as f32);
+                let picked = {
+                    let camera = world.read_resource::<Camera>();
+                    let entities = world.entities();
+                    let movement = world.read::<Movement>();
+                    let sel = world.read::<Selection>();
+                    (&entities, &movement, &sel).iter()
+                        .filter(|&(_, m, _)| match camera.world_to_screen(&m.position) {
+                            Some(p) => min_x <= p.x && p.x <= max_x && min_y <= p.y && p.y <= max_y,
+                            None => false,
+                        })
+                        .map(|(e, _, _)| e)
+                        .collect()
+                };
+                select(world, picked, mode);
+            }
+            Message::SelectEntities(ref picked, mode) => {
+                // only those still around
+                let picked = {
+                    let sel = world.read::<Selection>();
+                    picked.iter().cloned().filter(|&e| sel.get(e).is_some()).collect()
+                };
+                select(world, picked, mode);
+            }
+            _ => ()
+        }
+    }
+}
 
-                // set new current selection based on hovered location
-                match *hover {
-                    CurrentHover::Entity(e) => {
-                        curr_sel.0 = Some(e);
-                        sel.get_mut(e).map(|s| s.selected = true);
-                    }
-                    _ => curr_sel.0 = None,
+/// Combine `picked` with the current selection and mark everything accordingly.
+fn select(world: &mut World, picked: Vec<Entity>, mode: SelectionMode) {
+    let mut curr_sel = world.write_resource::<CurrentSelection>();
+    match mode {
+        SelectionMode::Replace => curr_sel.0 = picked.into_iter().collect(),
+        SelectionMode::Add => curr_sel.0.extend(picked),
+        SelectionMode::Toggle => {
+            for e in picked {
+                if !curr_sel.0.remove(&e) {
+                    curr_sel.0.insert(e);
                 }
             }
-            _ => ()
         }
     }
+
+    let entities = world.entities();
+    let mut sel = world.write::<Selection>();
+    for (e, s) in (&entities, &mut sel).iter() {
+        s.selected = curr_sel.0.contains(&e);
+    }
 }
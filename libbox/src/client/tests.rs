@@ -1,5 +1,92 @@
 use super::*;
 
+use common::testing::TempFile;
+
 #[test]
 fn test() {
 }
+
+#[test]
+fn bindings_file_overrides_only_what_it_mentions() {
+    use std::fs::File;
+    use std::io::Write;
+    use glium::glutin::MouseButton;
+    use glium::glutin::VirtualKeyCode as KC;
+
+    let file = TempFile::new("bindings_file_overrides_only_what_it_mentions");
+    let path = &file.0;
+    File::create(path).unwrap().write_all(b"# swap the mouse buttons\ningame select Mouse:Right\ningame command Mouse:Left\n\ningame quit Ctrl+Shift+Q\n").unwrap();
+
+    let bindings = Bindings::load(path).unwrap();
+    let left = Input::new(Trigger::Mouse(MouseButton::Left), Modifiers::none());
+    let right = Input::new(Trigger::Mouse(MouseButton::Right), Modifiers::none());
+    assert_eq!(bindings.action(Keybinds::InGame, &left), Some(Action::Command));
+    assert_eq!(bindings.action(Keybinds::InGame, &right), Some(Action::Select));
+
+    // the old quit key doesn't quit anymore, and modifiers have to match exactly
+    let escape = Input::new(Trigger::Key(KC::Escape), Modifiers::none());
+    let quit = Input::parse("Ctrl+Shift+Q").unwrap();
+    assert_eq!(bindings.action(Keybinds::InGame, &escape), None);
+    assert_eq!(bindings.action(Keybinds::InGame, &quit), Some(Action::Quit));
+    assert_eq!(bindings.action(Keybinds::InGame, &Input::new(Trigger::Key(KC::Q), Modifiers::ctrl())), None);
+
+    // everything else keeps its default, and contexts are separate
+    assert_eq!(bindings.action(Keybinds::InGame, &Input::parse("Ctrl+Key3").unwrap()), Some(Action::StoreGroup(3)));
+    assert_eq!(bindings.action(Keybinds::Menu, &escape), Some(Action::CancelChat));
+
+    // saving and loading again gives the same bindings
+    bindings.save(path).unwrap();
+    let reloaded = Bindings::load(path).unwrap();
+    for input in &[left, right, escape, quit] {
+        assert_eq!(reloaded.action(Keybinds::InGame, input), bindings.action(Keybinds::InGame, input));
+    }
+
+    File::create(path).unwrap().write_all(b"ingame fly Mouse:Left\n").unwrap();
+    assert!(Bindings::load(path).is_err());
+}
+
+#[test]
+fn camera_scrolls_at_the_edges_and_zoom_is_clamped() {
+    use std::collections::HashSet;
+    use time::Duration;
+    use specs::{Planner, World};
+    use nalgebra::Point2;
+    use client::systems::CameraSystem;
+
+    let cfg = ClientConfig::new();
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.add_resource(Camera::new(cfg.window_width, cfg.window_height, cfg.fov));
+    world.add_resource(CameraControl::new());
+    world.add_resource(CursorPosition(Point2::new(640, 360)));
+    world.add_resource(CurrentSelection(HashSet::new()));
+    let mut planner: Planner<Message, ClientSystemContext> = Planner::new(world, 1);
+    planner.add_system(CameraSystem::new(&cfg), "camera", 1);
+
+    let step = |planner: &mut Planner<Message, ClientSystemContext>| {
+        planner.dispatch(ClientSystemContext::new(Duration::milliseconds(100), cfg.timestep));
+        planner.handle_messages();
+        planner.mut_world().read_resource::<Camera>().target
+    };
+    let start = planner.mut_world().read_resource::<Camera>().target;
+
+    // the middle of the screen doesn't scroll
+    planner.mut_world().write_resource::<CameraControl>().focused = true;
+    assert_eq!(step(&mut planner), start);
+
+    // the left edge does, but not while the cursor is somewhere else
+    planner.mut_world().write_resource::<CursorPosition>().0 = Point2::new(2, 360);
+    let moved = step(&mut planner);
+    assert!(moved.x < start.x);
+    assert_eq!(moved.y, start.y);
+    planner.mut_world().write_resource::<CameraControl>().focused = false;
+    assert_eq!(step(&mut planner), moved);
+
+    // zooming stops at the limits however far it's asked to go
+    planner.mut_world().write_resource::<CameraControl>().zoom = 100.0;
+    step(&mut planner);
+    assert!((planner.mut_world().read_resource::<Camera>().distance() - cfg.camera_min_distance).abs() < 1.0e-3);
+    planner.mut_world().write_resource::<CameraControl>().zoom = -100.0;
+    step(&mut planner);
+    assert!((planner.mut_world().read_resource::<Camera>().distance() - cfg.camera_max_distance).abs() < 1.0e-3);
+}
@@ -1,5 +1,7 @@
+use std::collections::VecDeque;
+
 use nalgebra;
-use nalgebra::{Eye, Point3, Matrix4, Vector3};
+use nalgebra::{Eye, Norm, Point3, Matrix4, Vector2, Vector3};
 
 use specs;
 use specs::Component;
@@ -32,45 +34,273 @@ impl Component for Render {
 }
 
 
-#[derive(Clone, Copy, Debug)]
+/// Points to move through, in order.
+#[derive(Clone, Debug, Default, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Path {
+    pub waypoints: VecDeque<Point3<f32>>,
+}
+
+impl Path {
+    pub fn new() -> Path {
+        Path {
+            waypoints: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waypoints.is_empty()
+    }
+
+    /// The waypoint currently being moved towards.
+    pub fn next(&self) -> Option<&Point3<f32>> {
+        self.waypoints.front()
+    }
+
+    pub fn push(&mut self, waypoint: Point3<f32>) {
+        self.waypoints.push_back(waypoint);
+    }
+
+    pub fn clear(&mut self) {
+        self.waypoints.clear();
+    }
+}
+
+/// How an entity gets around. Entities without one move like `MovementStats::default()`.
+#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct MovementStats {
+    /// In units per ms
+    pub max_speed: f32,
+    /// How quickly we speed up, in units per ms per ms
+    pub acceleration: f32,
+    /// How quickly we slow down to stop at the end of the path, in units per ms per ms
+    pub deceleration: f32,
+    /// In radians per ms
+    pub turn_rate: f32,
+}
+
+impl MovementStats {
+    pub fn new(max_speed: f32, acceleration: f32, deceleration: f32, turn_rate: f32) -> MovementStats {
+        MovementStats {
+            max_speed: max_speed,
+            acceleration: acceleration,
+            deceleration: deceleration,
+            turn_rate: turn_rate,
+        }
+    }
+}
+
+impl Default for MovementStats {
+    fn default() -> MovementStats {
+        MovementStats {
+            max_speed: 0.002, // 2 units per s in units per ms
+            acceleration: 0.000004, // full speed in half a second
+            deceleration: 0.000008, // stop from full speed in a quarter of a second
+            turn_rate: 0.00628, // a full turn per second
+        }
+    }
+}
+
+impl Component for MovementStats {
+    type Storage = specs::VecStorage<MovementStats>;
+}
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct Movement {
-    // In the future we can change these to a Path
-    // and let position just be an f32 in [0,1]
-    // For now we just set the position to be lerped to target over time
     pub position: Point3<f32>,
-    pub speed: f32,
-    pub current_path: Option<(Point3<f32>, Point3<f32>, f32)>,
+    /// In units per ms
+    pub velocity: Vector3<f32>,
+    pub path: Path,
+}
+
+/// Velocity asked for on top of following the path, to keep out of others' way. It's eased in and
+/// out like any other change of speed.
+#[derive(Clone, Copy, Debug)]
+pub struct Steering {
+    /// In units per ms
+    pub desired: Vector3<f32>,
+    /// How much of the velocity is from steering right now, in units per ms
+    pub current: Vector3<f32>,
+}
+
+impl Steering {
+    pub fn new() -> Steering {
+        Steering {
+            desired: nalgebra::zero(),
+            current: nalgebra::zero(),
+        }
+    }
+}
+
+impl Component for Steering {
+    type Storage = specs::VecStorage<Steering>;
+}
+
+/// Closer than this to a waypoint counts as being there.
+const ARRIVAL_DISTANCE: f32 = 1.0e-4;
+
+/// Turn `heading` towards `direction` in the ground plane by at most `max_angle` radians. Both
+/// are unit vectors.
+fn turn_towards(heading: Vector3<f32>, direction: Vector3<f32>, max_angle: f32) -> Vector3<f32> {
+    use std::f32::consts::PI;
+
+    let current = heading.y.atan2(heading.x);
+    let mut difference = direction.y.atan2(direction.x) - current;
+    if difference > PI {
+        difference -= 2.0*PI;
+    }
+    else if difference < -PI {
+        difference += 2.0*PI;
+    }
+    if difference.abs() <= max_angle {
+        return direction;
+    }
+    let angle = current + max_angle*difference.signum();
+    Vector3::new(angle.cos(), angle.sin(), 0.0)
+}
+
+/// How much of `extra`, from 0 to 1, can be added to `velocity` without going faster than
+/// `max_speed`. `velocity` must not be faster than that already.
+fn room_for(velocity: Vector3<f32>, extra: Vector3<f32>, max_speed: f32) -> f32 {
+    let a = extra.norm_squared();
+    if a == 0.0 {
+        return 1.0;
+    }
+    // solve |velocity + s*extra| = max_speed for the positive s
+    let b = 2.0*nalgebra::dot(&velocity, &extra);
+    let c = velocity.norm_squared() - max_speed*max_speed;
+    let s = (-b + (b*b - 4.0*a*c).max(0.0).sqrt())/(2.0*a);
+    s.max(0.0).min(1.0)
 }
 
-const SPEED: f32 = 0.002; // 2 unit per s in units per ms
 impl Movement {
     pub fn new() -> Movement {
-        Movement {
-            position: Point3::new(0.0, 0.0, 0.0),
-            speed: 0.0,
-            current_path: None,
-        }
+        Movement::new_pos(Point3::new(0.0, 0.0, 0.0))
     }
 
     pub fn new_pos(position: Point3<f32>) -> Movement {
         Movement {
             position: position,
-            speed: 0.0,
-            current_path: None,
+            velocity: nalgebra::zero(),
+            path: Path::new(),
         }
     }
 
     pub fn new_pos_target(position: Point3<f32>, target: Point3<f32>) -> Movement {
-        Movement {
-            position: position,
-            speed: SPEED/nalgebra::distance(&position, &target),
-            current_path: Some((position, target, 0.0)),
-        }
+        let mut movement = Movement::new_pos(position);
+        movement.set_target(target);
+        movement
     }
 
+    /// Drop the current path and head straight for `target`.
     pub fn set_target(&mut self, target: Point3<f32>) {
-        self.speed = SPEED/nalgebra::distance(&self.position, &target);
-        self.current_path = Some((self.position, target, 0.0));
+        self.path.clear();
+        self.path.push(target);
+    }
+
+    /// Follow `waypoints` instead of the current path.
+    pub fn set_path(&mut self, waypoints: Vec<Point3<f32>>) {
+        self.path.waypoints = waypoints.into_iter().collect();
+    }
+
+    /// Go to `target` after the rest of the path.
+    pub fn queue_target(&mut self, target: Point3<f32>) {
+        self.path.push(target);
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.velocity.norm()
+    }
+
+    /// Move along the path for `ms` milliseconds: speeding up towards the top speed, turning
+    /// towards the next waypoint, and slowing down in time to stop on the last one. Waypoints
+    /// are passed through without stopping.
+    pub fn advance(&mut self, stats: &MovementStats, ms: f32) {
+        self.advance_steered(stats, &mut Steering::new(), ms);
+    }
+
+    /// Like `advance`, drifting by `steering` as well. Steering speeds up no faster than the path
+    /// does, and never takes us past our top speed.
+    pub fn advance_steered(&mut self, stats: &MovementStats, steering: &mut Steering, ms: f32) {
+        let target = match self.path.next() {
+            Some(&target) => target,
+            None => {
+                self.velocity = nalgebra::zero();
+                steering.current = nalgebra::zero();
+                return;
+            }
+        };
+
+        let to_target = target - self.position;
+        let distance = to_target.norm();
+        if distance < ARRIVAL_DISTANCE {
+            // nothing to do, and no direction to do it in
+            self.position = target;
+            self.path.waypoints.pop_front();
+            if self.path.is_empty() {
+                self.velocity = nalgebra::zero();
+                steering.current = nalgebra::zero();
+            }
+            return;
+        }
+        let direction = to_target/distance;
+
+        // standing still, we can face any way we like
+        let path_velocity = self.velocity - steering.current;
+        let speed = path_velocity.norm();
+        let heading = if speed > 0.0 {
+            turn_towards(path_velocity/speed, direction, stats.turn_rate*ms)
+        }
+        else {
+            direction
+        };
+
+        // ease into whatever steering is asked for
+        let wanted = steering.desired - steering.current;
+        let change = wanted.norm();
+        let max_change = stats.acceleration*ms;
+        if change > max_change {
+            steering.current = steering.current + wanted*(max_change/change);
+        }
+        else {
+            steering.current = steering.desired;
+        }
+
+        // fast enough that we can still stop on the last waypoint, no faster. slow down while
+        // facing the wrong way too, or we'd circle a waypoint we can't turn tightly enough to hit.
+        // and leave room for the steering within our top speed
+        let mut desired_speed = if self.path.waypoints.len() == 1 {
+            stats.max_speed.min((2.0*stats.deceleration*distance).sqrt())
+        }
+        else {
+            stats.max_speed
+        };
+        desired_speed *= nalgebra::dot(&heading, &direction).max(0.0);
+        let path_room = room_for(steering.current, heading*stats.max_speed, stats.max_speed);
+        desired_speed = desired_speed.min(stats.max_speed*path_room);
+        let new_speed = if desired_speed > speed {
+            desired_speed.min(speed + stats.acceleration*ms)
+        }
+        else {
+            desired_speed.max(speed - stats.deceleration*ms)
+        };
+        // the path can only slow down so fast, so the steering gives way if it has to
+        steering.current = steering.current*room_for(heading*new_speed, steering.current, stats.max_speed);
+
+        let step = new_speed*ms;
+        if step >= distance {
+            self.position = target;
+            self.path.waypoints.pop_front();
+            if self.path.is_empty() {
+                self.velocity = nalgebra::zero();
+                steering.current = nalgebra::zero();
+                return;
+            }
+        }
+        else {
+            self.position = self.position + heading*step;
+        }
+        self.position = self.position + steering.current*ms;
+        self.velocity = heading*new_speed + steering.current;
     }
 }
 
@@ -110,3 +340,59 @@ impl Controllable {
 impl Component for Controllable {
     type Storage = specs::NullStorage<Controllable>;
 }
+
+/// The player allowed to give an entity orders, by name. Names are what players authenticate
+/// as, so they keep their entities when they reconnect.
+#[derive(Clone, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Owner {
+    pub player: String,
+}
+
+impl Owner {
+    pub fn new(player: String) -> Owner {
+        Owner {
+            player: player,
+        }
+    }
+}
+
+impl Component for Owner {
+    type Storage = specs::VecStorage<Owner>;
+}
+
+/// The box an entity takes up for collisions, centered on its position.
+#[derive(Clone, Copy, Debug, RustcDecodable, RustcEncodable)]
+pub struct Collider {
+    pub half_extents: Vector2<f32>,
+}
+
+impl Collider {
+    pub fn new(half_width: f32, half_height: f32) -> Collider {
+        Collider {
+            half_extents: Vector2::new(half_width, half_height),
+        }
+    }
+}
+
+impl Component for Collider {
+    type Storage = specs::VecStorage<Collider>;
+}
+
+/// Something static that moving entities have to path around. Blocks a rectangle of the ground
+/// centered on the entity's position.
+#[derive(Clone, Copy, Debug, RustcDecodable, RustcEncodable)]
+pub struct Obstacle {
+    pub half_extents: Vector2<f32>,
+}
+
+impl Obstacle {
+    pub fn new(half_width: f32, half_height: f32) -> Obstacle {
+        Obstacle {
+            half_extents: Vector2::new(half_width, half_height),
+        }
+    }
+}
+
+impl Component for Obstacle {
+    type Storage = specs::VecStorage<Obstacle>;
+}
@@ -0,0 +1,132 @@
+//! LAN server discovery.
+//!
+//! Servers listen for `DiscoveryMessage::Query` datagrams on a well-known udp port and answer
+//! each one with an `Announce` carrying their info and game port. Clients broadcast a query and
+//! collect the answers.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+#[cfg(feature = "client")]
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json;
+
+use common::ServerInfo;
+
+pub const DISCOVERY_PORT: u16 = 8845;
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum DiscoveryMessage {
+    Query,
+    /// The server's info and the tcp port it accepts game connections on
+    Announce(ServerInfo, u16),
+}
+
+/// A server that answered a discovery query.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    pub address: SocketAddr,
+    pub info: ServerInfo,
+}
+
+/// Answers discovery queries on behalf of a server.
+#[cfg(feature = "server")]
+pub struct DiscoveryResponder {
+    socket: UdpSocket,
+}
+
+#[cfg(feature = "server")]
+impl DiscoveryResponder {
+    pub fn new(port: u16) -> io::Result<DiscoveryResponder> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+        Ok(DiscoveryResponder {
+            socket: socket,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Answer every query that has arrived since the last call, from anyone who could connect
+    /// to a game listening on `game_address`.
+    pub fn respond(&self, info: &ServerInfo, game_address: SocketAddr) {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(_) => break, // WouldBlock, nothing left to answer
+            };
+
+            if !reachable(&game_address, &from) {
+                continue;
+            }
+
+            let query = String::from_utf8_lossy(&buf[..len]);
+            match json::decode(&query) {
+                Ok(DiscoveryMessage::Query) => {
+                    let announce = DiscoveryMessage::Announce(info.clone(), game_address.port());
+                    let announce = json::encode(&announce).unwrap();
+                    if let Err(error) = self.socket.send_to(announce.as_bytes(), from) {
+                        println!("could not answer discovery query from {}: {:?}", from, error);
+                    }
+                }
+                Ok(_) => (),
+                Err(error) => println!("bad discovery query from {}: {:?}", from, error),
+            }
+        }
+    }
+}
+
+/// Whether `peer` can connect to a game listening on `game_address`. Queries arrive on every
+/// interface, but a game only listening on loopback can't be reached from anywhere else.
+#[cfg(feature = "server")]
+pub fn reachable(game_address: &SocketAddr, peer: &SocketAddr) -> bool {
+    !game_address.ip().is_loopback() || peer.ip().is_loopback()
+}
+
+/// Send a discovery query to `target` (usually the broadcast address and `DISCOVERY_PORT`) and
+/// collect every server that answers within `timeout`.
+#[cfg(feature = "client")]
+pub fn discover_servers(target: SocketAddr, timeout: Duration) -> io::Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+
+    let query = json::encode(&DiscoveryMessage::Query).unwrap();
+    socket.send_to(query.as_bytes(), target)?;
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buf = [0u8; 2048];
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock ||
+                              error.kind() == io::ErrorKind::TimedOut => break,
+            Err(error) => return Err(error),
+        };
+
+        let reply = String::from_utf8_lossy(&buf[..len]);
+        match json::decode(&reply) {
+            Ok(DiscoveryMessage::Announce(info, port)) => {
+                let address = SocketAddr::new(from.ip(), port);
+                if !servers.iter().any(|s| s.address == address) {
+                    servers.push(DiscoveredServer { address: address, info: info });
+                }
+            }
+            Ok(_) => (),
+            Err(error) => println!("bad discovery reply from {}: {:?}", from, error),
+        }
+    }
+
+    Ok(servers)
+}
@@ -1,23 +1,281 @@
+use std::ops::BitOr;
+
 use specs::Entity;
 
+use nalgebra::{Point2, Point3};
+
+use common::replication::{Replication, Snapshot};
 use common::resources::{CurrentHover};
 
+/// How newly picked entities combine with the current selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum SelectionMode {
+    /// Select only the picked entities
+    Replace,
+    /// Add the picked entities to the selection
+    Add,
+    /// Select the picked entities that weren't selected, and deselect the ones that were
+    Toggle,
+}
+
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub enum Message {
-    SelectEntity,
-    InteractWith(Entity, CurrentHover),
+    /// Select whatever is under the cursor
+    SelectEntity(SelectionMode),
+    /// Select everything on screen inside the rectangle between two corners, in pixels
+    SelectArea(Point2<i32>, Point2<i32>, SelectionMode),
+    /// Select these particular entities
+    SelectEntities(Vec<Entity>, SelectionMode),
+    /// Order an entity to interact with what's under the cursor. The first field is the client
+    /// giving the order: the server fills it in from the connection the order came in on, so
+    /// whatever the client sends there is ignored.
+    InteractWith(u16, Entity, CurrentHover),
+    /// Like `InteractWith`, but after whatever the entity is already doing
+    QueueInteraction(u16, Entity, CurrentHover),
+    /// Like `InteractWith`, for several entities at once. The server spreads them out around the
+    /// target so they don't all end up in one spot. If the flag is set, the command is queued
+    /// like `QueueInteraction`.
+    CommandGroup(u16, Vec<Entity>, CurrentHover, bool),
+    /// Text typed by the local player, to be sent to the server
+    SendChat(String),
+    /// Changes to the entities near the player, received from the server
+    Replicate(Vec<Replication>),
     Quit,
 }
 
+impl Message {
+    /// This message as given by `client_id`, for orders received from a client.
+    pub fn sent_by(self, client_id: u16) -> Message {
+        match self {
+            Message::InteractWith(_, e, hover) => Message::InteractWith(client_id, e, hover),
+            Message::QueueInteraction(_, e, hover) => Message::QueueInteraction(client_id, e, hover),
+            Message::CommandGroup(_, group, hover, queue) => Message::CommandGroup(client_id, group, hover, queue),
+            other => other,
+        }
+    }
+}
+
+/// Version of the network protocol, bumped whenever a change to `NetworkMessage`, anything sent
+/// inside it, or its framing would break older peers. This is separate from the crate version so
+/// that releases which don't touch the protocol stay compatible with each other.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The oldest protocol version this build can still speak. Raise it along with
+/// `PROTOCOL_VERSION` unless the older messages can still be decoded.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// An inclusive range of protocol versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct ProtocolRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl ProtocolRange {
+    /// The range of versions this build supports.
+    pub fn supported() -> ProtocolRange {
+        ProtocolRange {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn contains(&self, version: u32) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// The newest version in both ranges, if they overlap.
+    pub fn best_common(&self, other: &ProtocolRange) -> Option<u32> {
+        let max = self.max.min(other.max);
+        if max >= self.min.max(other.min) {
+            Some(max)
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// Bit flags for optional protocol features. Each side sends the features it supports when
+/// connecting, and only the features both sides support are used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Send the message of the day after the handshake
+    pub const MOTD: Capabilities = Capabilities(1 << 0);
+    /// Send and receive text chat
+    pub const CHAT: Capabilities = Capabilities(1 << 1);
+    /// Exchange periodic pings, and drop the connection if the other end goes quiet
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 2);
+    /// Receive the entities near the client's view from the server
+    pub const REPLICATION: Capabilities = Capabilities(1 << 3);
+
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Every feature this build supports.
+    pub fn supported() -> Capabilities {
+        Capabilities::MOTD | Capabilities::CHAT | Capabilities::HEARTBEAT | Capabilities::REPLICATION
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// Crate version of the sender, for logging and display only. Compatibility is decided by the
+/// protocol version.
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct Version(pub String);
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub struct DisconnectReason(pub String);
 
+/// Sent by the client to start the handshake.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ConnectRequest {
+    pub version: Version,
+    pub protocols: ProtocolRange,
+    pub capabilities: Capabilities,
+    pub name: String,
+    /// Password or other secret, if the server requires one
+    pub token: Option<String>,
+}
+
+impl ConnectRequest {
+    pub fn new(name: String, token: Option<String>) -> ConnectRequest {
+        ConnectRequest {
+            version: Version(env!("CARGO_PKG_VERSION").to_owned()),
+            protocols: ProtocolRange::supported(),
+            capabilities: Capabilities::supported(),
+            name: name,
+            token: token,
+        }
+    }
+}
+
+/// Sent by the server when it accepts a `ConnectRequest`, with the protocol version and
+/// capabilities that will be used for the rest of the connection.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct Welcome {
+    pub version: Version,
+    pub protocol: u32,
+    pub capabilities: Capabilities,
+}
+
+/// Why the server refused a `ConnectRequest`.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum RejectReason {
+    /// The server's supported protocol versions and the client's, which don't overlap
+    Version(ProtocolRange, ProtocolRange),
+    ServerFull,
+    /// The name was empty, too long, or contained control characters
+    InvalidName,
+    /// Another connected player already has this name
+    NameTaken,
+    AuthenticationFailed,
+}
+
+/// A line of chat relayed by the server. `from` is None for notices from the server itself.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ChatMessage {
+    pub from: Option<String>,
+    pub text: String,
+}
+
+/// Public information about a server, available without connecting to it.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+    pub protocols: ProtocolRange,
+    pub player_count: u16,
+    pub capacity: u16,
+    pub uptime_secs: u64,
+}
+
+/// The rectangle of the ground plane a client is looking at.
+#[derive(Clone, Copy, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ViewArea {
+    pub min: Point2<f32>,
+    pub max: Point2<f32>,
+}
+
+impl ViewArea {
+    pub fn new(min: Point2<f32>, max: Point2<f32>) -> ViewArea {
+        ViewArea {
+            min: min,
+            max: max,
+        }
+    }
+
+    /// Whether `position` is inside the area grown by `margin` on every side.
+    pub fn contains(&self, position: &Point3<f32>, margin: f32) -> bool {
+        self.min.x - margin <= position.x && position.x <= self.max.x + margin &&
+        self.min.y - margin <= position.y && position.y <= self.max.y + margin
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.min.x.is_finite() && self.min.y.is_finite() && self.max.x.is_finite() && self.max.y.is_finite()
+    }
+
+    /// The part of the area inside the rectangle between `min` and `max`. An area entirely
+    /// outside it is squashed flat against its nearest edge.
+    pub fn within(&self, min: Point2<f32>, max: Point2<f32>) -> ViewArea {
+        let clamp = |p: Point2<f32>| Point2::new(p.x.max(min.x).min(max.x), p.y.max(min.y).min(max.y));
+        ViewArea::new(clamp(self.min), clamp(self.max))
+    }
+
+    /// The same area, shrunk around its center if it is more than `extent` wide or tall.
+    pub fn clamped(&self, extent: f32) -> ViewArea {
+        let clamp = |min: f32, max: f32| {
+            if max - min <= extent {
+                (min, max)
+            }
+            else {
+                let center = (min + max)/2.0;
+                (center - extent/2.0, center + extent/2.0)
+            }
+        };
+        let (min_x, max_x) = clamp(self.min.x, self.max.x);
+        let (min_y, max_y) = clamp(self.min.y, self.max.y);
+        ViewArea::new(Point2::new(min_x, min_y), Point2::new(max_x, max_y))
+    }
+}
+
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub enum NetworkMessage {
     GameMessage(Message),
-    Connect(Version),
+    Connect(ConnectRequest),
+    Accepted(Welcome),
+    Rejected(RejectReason),
     Motd(String),
     Disconnect(DisconnectReason),
+    ServerInfoRequest,
+    ServerInfoResponse(ServerInfo),
+    /// Chat text from a client, to be relayed to everyone
+    SendChat(String),
+    Chat(ChatMessage),
+    /// Sent by either side, which expects a `Pong` with the same sequence number back
+    Ping(u64),
+    Pong(u64),
+    /// Sent by the client whenever its camera shows a different area
+    View(ViewArea),
+    /// Entity changes relative to the last snapshot the client acknowledged, sent by the server
+    Replicate(Snapshot),
+    /// Sent by the client for every snapshot it has applied
+    AckSnapshot(u32),
 }
@@ -1,5 +1,14 @@
 mod messages;
 pub use self::messages::*;
 
+pub mod discovery;
+pub mod net;
+pub mod replication;
 pub mod resources;
 pub mod components;
+
+// only the client and server tests use these
+#[cfg(all(test, any(feature = "client", feature = "server")))]
+pub mod testing;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,437 @@
+//! Framing and transport for `NetworkMessage`s sent over a tcp stream.
+//!
+//! Each message is a single line of json terminated by `\n`. The json encoder escapes newlines
+//! inside strings, so a raw newline always marks the end of a message.
+//!
+//! A `Connection` may optionally encrypt the stream with a Noise_NX handshake: the server has a
+//! static keypair (which clients can pin, much like a self-signed certificate) and the client is
+//! anonymous. Once the handshake is done, the same json lines are sent inside encrypted frames,
+//! each a 2 byte big-endian length followed by that many bytes of ciphertext.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json;
+
+use snow;
+
+use time::{Duration, PreciseTime};
+
+use common::NetworkMessage;
+
+const NOISE_PARAMS: &'static str = "Noise_NX_25519_ChaChaPoly_BLAKE2s";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+
+/// How the server secures incoming connections.
+#[derive(Clone, Debug)]
+pub enum ServerEncryption {
+    Plaintext,
+    /// Noise handshake using this static private key. If no key is given, a new one is generated
+    /// when the server starts.
+    Noise(Option<Vec<u8>>),
+}
+
+/// How the client secures its connection to the server.
+#[derive(Clone, Debug)]
+pub enum ClientEncryption {
+    Plaintext,
+    /// Noise handshake, optionally checking that the server's static public key is this one.
+    Noise(Option<Vec<u8>>),
+}
+
+/// Parse a hex encoded noise key, e.g. from the command line.
+pub fn decode_key(hex: &str) -> io::Result<Vec<u8>> {
+    hex.from_hex().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+}
+
+/// Generate a new static keypair for a server, returning `(private, public)`.
+pub fn generate_keypair() -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+        .generate_keypair()
+        .map_err(noise_error)?;
+    Ok((keypair.private, keypair.public))
+}
+
+fn noise_error(error: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise error: {}", error))
+}
+
+pub fn encode_message(msg: &NetworkMessage) -> Vec<u8> {
+    let mut bytes = json::encode(msg).unwrap().into_bytes();
+    bytes.push(b'\n');
+    bytes
+}
+
+/// Read what is currently available from `stream`, up to `limit` bytes, onto the end of `buf` and
+/// return the number of bytes read. Returns an `UnexpectedEof` error once the other end has
+/// closed the stream.
+fn read_available<R: Read>(stream: &mut R, buf: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    let mut chunk = [0u8; 4096];
+    let mut total = 0;
+    while total < limit {
+        let wanted = chunk.len().min(limit - total);
+        match stream.read(&mut chunk[..wanted]) {
+            Ok(0) => {
+                if total > 0 {
+                    return Ok(total);
+                }
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                total += n;
+                // a short read means there's nothing more right now, so don't wait on a
+                // blocking stream
+                if n < wanted {
+                    return Ok(total);
+                }
+            }
+            Err(error) => {
+                match error.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(total),
+                    io::ErrorKind::Interrupted => (),
+                    _ => return Err(error),
+                }
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Buffers bytes read from a stream until they form complete messages.
+pub struct MessageReader {
+    buf: Vec<u8>,
+    max_message_size: usize,
+}
+
+impl MessageReader {
+    pub fn new() -> MessageReader {
+        MessageReader {
+            buf: Vec::new(),
+            max_message_size: usize::max_value(),
+        }
+    }
+
+    /// Make `read_from` fail with an `InvalidData` error if a message is longer than `size` bytes.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Most to read in one go. One byte past the longest message is enough to tell it's too
+    /// long, so a peer that never ends its message can't make us buffer more than that.
+    fn read_limit(&self) -> usize {
+        self.max_message_size.saturating_add(1)
+    }
+
+    /// Returns an error if any message in the buffer, complete or not, is longer than
+    /// `max_message_size`.
+    fn check_size(&self) -> io::Result<()> {
+        if self.buf.split(|&b| b == b'\n').any(|line| line.len() > self.max_message_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+        }
+        Ok(())
+    }
+
+    /// Read what is currently available from `stream` into the buffer, up to a bit more than
+    /// the largest message, and return the number of bytes read. Returns an `UnexpectedEof` error
+    /// once the other end has closed the stream.
+    pub fn read_from<R: Read>(&mut self, stream: &mut R) -> io::Result<usize> {
+        let limit = self.read_limit();
+        let n = read_available(stream, &mut self.buf, limit)?;
+        self.check_size()?;
+        Ok(n)
+    }
+
+    /// Add bytes that were received some other way, e.g. decrypted.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Decode the next complete message in the buffer, if there is one.
+    pub fn next_message(&mut self) -> Option<Result<NetworkMessage, json::DecoderError>> {
+        let end = match self.buf.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => return None,
+        };
+
+        let line: Vec<u8> = self.buf.drain(..end + 1).collect();
+        let text = String::from_utf8_lossy(&line[..end]);
+        Some(json::decode(&text))
+    }
+}
+
+enum Security {
+    Plaintext,
+    Handshake(Box<snow::HandshakeState>),
+    Encrypted(Box<snow::TransportState>),
+}
+
+/// A stream of `NetworkMessage`s, optionally encrypted.
+pub struct Connection {
+    stream: TcpStream,
+    reader: MessageReader,
+    security: Security,
+    /// Encrypted bytes that don't yet make up a whole frame
+    frames: Vec<u8>,
+    /// Messages sent before the handshake finished
+    pending: Vec<u8>,
+    /// Bytes waiting for the socket to accept them
+    outgoing: Vec<u8>,
+    /// The server key the client expects to see in the handshake
+    pinned_key: Option<Vec<u8>>,
+}
+
+impl Connection {
+    pub fn plaintext(stream: TcpStream) -> Connection {
+        Connection {
+            stream: stream,
+            reader: MessageReader::new(),
+            security: Security::Plaintext,
+            frames: Vec::new(),
+            pending: Vec::new(),
+            outgoing: Vec::new(),
+            pinned_key: None,
+        }
+    }
+
+    /// Start a client-side connection, sending the first handshake message.
+    pub fn noise_initiator(stream: TcpStream, pinned_key: Option<Vec<u8>>) -> io::Result<Connection> {
+        let handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .build_initiator()
+            .map_err(noise_error)?;
+
+        let mut connection = Connection::plaintext(stream);
+        connection.security = Security::Handshake(Box::new(handshake));
+        connection.pinned_key = pinned_key;
+        connection.continue_handshake()?;
+        Ok(connection)
+    }
+
+    /// Start a server-side connection, which waits for the client's first handshake message.
+    pub fn noise_responder(stream: TcpStream, private_key: &[u8]) -> io::Result<Connection> {
+        let handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(private_key)
+            .build_responder()
+            .map_err(noise_error)?;
+
+        let mut connection = Connection::plaintext(stream);
+        connection.security = Security::Handshake(Box::new(handshake));
+        Ok(connection)
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Make `receive` fail with an `InvalidData` error if the other end sends a message longer
+    /// than `size` bytes.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.reader.set_max_message_size(size);
+    }
+
+    /// Queue a message and send as much of the queue as the socket will take. Messages sent
+    /// during the handshake are held until it completes.
+    pub fn send(&mut self, msg: &NetworkMessage) -> io::Result<()> {
+        let bytes = encode_message(msg);
+        match self.security {
+            Security::Plaintext => self.outgoing.extend_from_slice(&bytes),
+            Security::Handshake(_) => self.pending.extend_from_slice(&bytes),
+            Security::Encrypted(ref mut transport) => write_encrypted(&mut self.outgoing, transport, &bytes)?,
+        }
+        self.flush()
+    }
+
+    /// Write as much of the outgoing queue as the socket will take. On a nonblocking socket this
+    /// never waits; whatever doesn't fit stays queued for the next call.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(error) => {
+                    match error.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => return Ok(()),
+                        io::ErrorKind::Interrupted => (),
+                        _ => return Err(error),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes sent but not yet accepted by the socket.
+    pub fn queued_bytes(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Read whatever has arrived on the stream and return the number of bytes read. Any complete
+    /// messages are then available from `next_message`.
+    pub fn receive(&mut self) -> io::Result<usize> {
+        match self.security {
+            Security::Plaintext => self.reader.read_from(&mut self.stream),
+            _ => {
+                // frames are decrypted as soon as they're whole, so this only ever holds one
+                // partial frame on top of what was just read
+                let limit = self.reader.read_limit();
+                let n = read_available(&mut self.stream, &mut self.frames, limit)?;
+                self.process_frames()?;
+                self.reader.check_size()?;
+                Ok(n)
+            }
+        }
+    }
+
+    pub fn next_message(&mut self) -> Option<Result<NetworkMessage, json::DecoderError>> {
+        self.reader.next_message()
+    }
+
+    fn process_frames(&mut self) -> io::Result<()> {
+        while self.frames.len() >= 2 {
+            let len = ((self.frames[0] as usize) << 8) | self.frames[1] as usize;
+            if self.frames.len() < 2 + len {
+                break;
+            }
+            let frame: Vec<u8> = self.frames.drain(..2 + len).skip(2).collect();
+
+            let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+            match self.security {
+                Security::Handshake(ref mut handshake) => {
+                    handshake.read_message(&frame, &mut payload).map_err(noise_error)?;
+                }
+                Security::Encrypted(ref mut transport) => {
+                    let n = transport.read_message(&frame, &mut payload).map_err(noise_error)?;
+                    self.reader.push(&payload[..n]);
+                }
+                Security::Plaintext => unreachable!(),
+            }
+            self.continue_handshake()?;
+        }
+        Ok(())
+    }
+
+    /// Send our next handshake message if it's our turn, and switch to transport mode once the
+    /// handshake is done.
+    fn continue_handshake(&mut self) -> io::Result<()> {
+        let finished = match self.security {
+            Security::Handshake(ref mut handshake) => {
+                if !handshake.is_handshake_finished() && handshake.is_my_turn() {
+                    let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+                    let n = handshake.write_message(&[], &mut buf).map_err(noise_error)?;
+                    write_frame(&mut self.outgoing, &buf[..n]);
+                }
+                handshake.is_handshake_finished()
+            }
+            _ => return Ok(()),
+        };
+        if !finished {
+            return self.flush();
+        }
+
+        let handshake = match mem::replace(&mut self.security, Security::Plaintext) {
+            Security::Handshake(handshake) => handshake,
+            _ => unreachable!(),
+        };
+        if let Some(ref key) = self.pinned_key {
+            if handshake.get_remote_static() != Some(&key[..]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "server key does not match the pinned key"));
+            }
+        }
+        let mut transport = handshake.into_transport_mode().map_err(noise_error)?;
+
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        write_encrypted(&mut self.outgoing, &mut transport, &pending)?;
+        self.security = Security::Encrypted(Box::new(transport));
+        self.flush()
+    }
+}
+
+fn write_frame(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push((bytes.len() >> 8) as u8);
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+fn write_encrypted(out: &mut Vec<u8>, transport: &mut snow::TransportState, bytes: &[u8]) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+    for chunk in bytes.chunks(MAX_NOISE_MESSAGE - NOISE_TAG_LEN) {
+        let n = transport.write_message(chunk, &mut buf).map_err(noise_error)?;
+        write_frame(out, &buf[..n]);
+    }
+    Ok(())
+}
+
+/// Tracks the pings sent on a connection, to estimate the round trip time and to notice when the
+/// other end has gone quiet for too long.
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    last_received: PreciseTime,
+    last_ping: PreciseTime,
+    /// The sequence number and send time of the ping we're waiting on
+    outstanding: Option<(u64, PreciseTime)>,
+    sequence: u64,
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Heartbeat {
+        let now = PreciseTime::now();
+        Heartbeat {
+            interval: interval,
+            timeout: timeout,
+            last_received: now,
+            last_ping: now,
+            outstanding: None,
+            sequence: 0,
+            rtt: None,
+        }
+    }
+
+    /// Call whenever anything arrives from the other end.
+    pub fn received(&mut self) {
+        self.last_received = PreciseTime::now();
+    }
+
+    /// Returns a ping to send if it's time for one.
+    pub fn poll(&mut self) -> Option<NetworkMessage> {
+        let now = PreciseTime::now();
+        if self.last_ping.to(now) < self.interval {
+            return None;
+        }
+
+        self.sequence += 1;
+        self.last_ping = now;
+        self.outstanding = Some((self.sequence, now));
+        Some(NetworkMessage::Ping(self.sequence))
+    }
+
+    /// Call when a pong arrives, to update the round trip time.
+    pub fn pong(&mut self, sequence: u64) {
+        let sent = match self.outstanding {
+            Some((s, sent)) if s == sequence => sent,
+            _ => return, // late or bogus
+        };
+        self.outstanding = None;
+
+        // smoothed the same way as tcp's srtt
+        let sample = sent.to(PreciseTime::now());
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt*7 + sample)/8,
+            None => sample,
+        });
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_received.to(PreciseTime::now()) > self.timeout
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
@@ -0,0 +1,120 @@
+//! What the server sends clients about entities, kept small: positions are quantized to fixed
+//! point, and after an entity has been spawned only the parts of it that changed are sent.
+
+use specs::Entity;
+
+use nalgebra::Point3;
+
+use common::components::{Movement, MovementStats, Path};
+
+/// Fixed point units per world unit.
+pub const POSITION_SCALE: f32 = 100.0;
+
+/// A position rounded to `1/POSITION_SCALE` of a unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct QuantizedPoint(pub i32, pub i32, pub i32);
+
+impl QuantizedPoint {
+    pub fn new(p: &Point3<f32>) -> QuantizedPoint {
+        let q = |v: f32| (v*POSITION_SCALE).round() as i32;
+        QuantizedPoint(q(p.x), q(p.y), q(p.z))
+    }
+
+    pub fn to_point(&self) -> Point3<f32> {
+        Point3::new(self.0 as f32/POSITION_SCALE, self.1 as f32/POSITION_SCALE, self.2 as f32/POSITION_SCALE)
+    }
+}
+
+/// Everything a client is told about an entity.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct EntityState {
+    pub position: QuantizedPoint,
+    /// Waypoints still to be reached, in order
+    pub path: Vec<QuantizedPoint>,
+    pub stats: MovementStats,
+    pub controllable: bool,
+}
+
+/// Turn replicated waypoints back into a `Path`.
+pub fn path_from(waypoints: &[QuantizedPoint]) -> Path {
+    Path {
+        waypoints: waypoints.iter().map(QuantizedPoint::to_point).collect(),
+    }
+}
+
+impl EntityState {
+    pub fn new(m: &Movement, stats: &MovementStats, controllable: bool) -> EntityState {
+        EntityState {
+            position: QuantizedPoint::new(&m.position),
+            path: m.path.waypoints.iter().map(QuantizedPoint::new).collect(),
+            stats: *stats,
+            controllable: controllable,
+        }
+    }
+
+    pub fn to_movement(&self) -> Movement {
+        let mut movement = Movement::new_pos(self.position.to_point());
+        movement.path = path_from(&self.path);
+        movement
+    }
+
+    /// The changes that turn `baseline` into this state.
+    pub fn delta_from(&self, entity: Entity, baseline: &EntityState) -> Vec<Replication> {
+        let mut changes = Vec::new();
+        if self.position != baseline.position {
+            changes.push(Replication::Moved(entity, self.position));
+        }
+        if self.path != baseline.path {
+            changes.push(Replication::PathChanged(entity, self.path.clone()));
+        }
+        if self.stats != baseline.stats {
+            changes.push(Replication::StatsChanged(entity, self.stats));
+        }
+        changes
+    }
+
+    /// Apply a change to this entity's state.
+    pub fn apply(&mut self, change: &Replication) {
+        match *change {
+            Replication::Spawn(_, ref state) => *self = state.clone(),
+            Replication::Moved(_, position) => self.position = position,
+            Replication::PathChanged(_, ref path) => self.path = path.clone(),
+            Replication::StatsChanged(_, stats) => self.stats = stats,
+            Replication::Despawn(_) | Replication::Destroyed(_) => (),
+        }
+    }
+}
+
+/// A change to one of the entities a client knows about. Entities are identified by their id on
+/// the server.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum Replication {
+    /// The entity came into view
+    Spawn(Entity, EntityState),
+    Moved(Entity, QuantizedPoint),
+    /// The entity's remaining waypoints changed
+    PathChanged(Entity, Vec<QuantizedPoint>),
+    /// The entity's speed, acceleration or turn rate changed
+    StatsChanged(Entity, MovementStats),
+    /// The entity left the view. It still exists, and may come back
+    Despawn(Entity),
+    /// The entity no longer exists, and never will again
+    Destroyed(Entity),
+}
+
+impl Replication {
+    pub fn entity(&self) -> Entity {
+        match *self {
+            Replication::Spawn(e, _) | Replication::Moved(e, _) | Replication::PathChanged(e, _) |
+            Replication::StatsChanged(e, _) | Replication::Despawn(e) | Replication::Destroyed(e) => e,
+        }
+    }
+}
+
+/// One batch of changes. The client acknowledges each sequence number so the server knows which
+/// state further changes can be relative to.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct Snapshot {
+    pub sequence: u32,
+    pub changes: Vec<Replication>,
+}
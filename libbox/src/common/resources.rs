@@ -1,13 +1,127 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use specs::Entity;
 
 use nalgebra;
-use nalgebra::{Inverse, Isometry3, Point2, Point3, Matrix4, Norm, PerspectiveMatrix3, ToHomogeneous, Vector3, Vector4};
+use nalgebra::{Inverse, Isometry3, Point2, Point3, Matrix4, Norm, PerspectiveMatrix3, ToHomogeneous, Vector2, Vector3, Vector4};
 
 use ncollide::query::Ray;
 
+use time::Duration;
+
+use common::{ChatMessage, ViewArea};
+
 #[derive(Clone, Debug)]
 pub struct IsRunning(pub bool);
 
+/// Smoothed round trip time to the server, once it has been measured
+#[derive(Clone, Debug)]
+pub struct Latency(pub Option<Duration>);
+
+/// Chat messages received from the server, oldest first. Only the most recent `capacity` are kept.
+#[derive(Clone, Debug)]
+pub struct ChatLog {
+    pub messages: VecDeque<ChatMessage>,
+    pub capacity: usize,
+}
+
+impl ChatLog {
+    pub fn new(capacity: usize) -> ChatLog {
+        ChatLog {
+            messages: VecDeque::new(),
+            capacity: capacity,
+        }
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+}
+
+/// Names of the authenticated players currently connected to the server, by client id
+#[derive(Clone, Debug)]
+pub struct Players(pub HashMap<u16, String>);
+
+/// Traffic counters for one client connection
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    pub bytes_received: u64,
+    pub messages_received: u64,
+    pub malformed_messages: u64,
+    /// Messages dropped for going over the message rate limit
+    pub dropped_messages: u64,
+    /// Times any limit was broken
+    pub violations: u64,
+    /// Smoothed round trip time, once measured
+    pub rtt: Option<Duration>,
+    /// Bytes waiting to be sent to the client
+    pub bytes_queued: u64,
+}
+
+/// Traffic counters for every connection to the server, by client id, for spotting misbehaving
+/// clients
+#[derive(Clone, Debug)]
+pub struct ConnectionStats(pub HashMap<u16, ClientStats>);
+
+/// The area each client is looking at, by client id. Clients that haven't told us yet, or don't
+/// support replication, aren't sent any entities.
+#[derive(Clone, Debug)]
+pub struct ClientViews(pub HashMap<u16, ViewArea>);
+
+/// The latest snapshot each client has acknowledged, by client id
+#[derive(Clone, Debug)]
+pub struct SnapshotAcks(pub HashMap<u16, u32>);
+
+/// Replication traffic to one client
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthStats {
+    pub last_update_bytes: usize,
+    /// Smoothed over recent updates
+    pub average_update_bytes: f32,
+    /// What an update would have cost sending every visible entity in full. Only worked out
+    /// for replication reports, as of the last one
+    pub full_state_bytes: usize,
+    /// Entities whose changes didn't fit in the last update
+    pub deferred_changes: usize,
+}
+
+/// Replication traffic for every client, by client id
+#[derive(Clone, Debug)]
+pub struct Bandwidth(pub HashMap<u16, BandwidthStats>);
+
+/// Pairs each entity replicated from the server with the local entity standing in for it.
+#[derive(Clone, Debug)]
+pub struct ReplicatedEntities {
+    pub to_local: HashMap<Entity, Entity>,
+    pub to_server: HashMap<Entity, Entity>,
+}
+
+impl ReplicatedEntities {
+    pub fn new() -> ReplicatedEntities {
+        ReplicatedEntities {
+            to_local: HashMap::new(),
+            to_server: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, server: Entity, local: Entity) {
+        self.to_local.insert(server, local);
+        self.to_server.insert(local, server);
+    }
+
+    /// Forget a server entity, returning the local entity that stood in for it.
+    pub fn remove(&mut self, server: Entity) -> Option<Entity> {
+        let local = self.to_local.remove(&server);
+        if let Some(ref local) = local {
+            self.to_server.remove(local);
+        }
+        local
+    }
+}
+
 
 #[derive(Clone, Debug)]
 pub struct Camera {
@@ -60,28 +174,203 @@ impl Camera {
         self.view = Isometry3::look_at_rh(&self.position, &self.target, &UP);
     }
 
+    /// Move the camera and what it's looking at together.
+    pub fn translate(&mut self, offset: Vector3<f32>) {
+        self.target = self.target + offset;
+        let position = self.position + offset;
+        self.move_to(position);
+    }
+
+    /// How far the camera is from what it's looking at.
+    pub fn distance(&self) -> f32 {
+        nalgebra::distance(&self.position, &self.target)
+    }
+
+    /// Move towards or away from what we're looking at until we're `distance` away.
+    pub fn set_distance(&mut self, distance: f32) {
+        let direction = (self.position - self.target).normalize();
+        let position = self.target + direction*distance;
+        self.move_to(position);
+    }
+
+    pub fn screen_size(&self) -> Vector2<f32> {
+        Vector2::new(self.width, self.height)
+    }
+
+    /// Where the ray through a point on screen hits the ground, if it does.
+    pub fn ground_at(&self, p: Point2<i32>) -> Option<Point3<f32>> {
+        let ray = self.ray_from_screen(p);
+        if ray.dir.z >= 0.0 {
+            return None;
+        }
+        let mut ground = ray.origin + (-ray.origin.z/ray.dir.z)*ray.dir;
+        ground.z = 0.0;
+        Some(ground)
+    }
+
+    /// Move so that `point` is in the middle of the view, looking at it from the same angle.
+    pub fn center_on(&mut self, point: Point3<f32>) {
+        let offset = self.position - self.target;
+        self.target = point;
+        self.move_to(point + offset);
+    }
+
+    /// The window was resized: keep the picture from stretching and clicks landing where they
+    /// show up.
     pub fn set_screen_size(&mut self, width: u32, height: u32) {
         self.width = width as f32;
         self.height = height as f32;
+        self.persp.set_aspect(self.width/self.height);
     }
 
     /// Returns a normalized ray pointing from the camera's position into the scene
     /// projected from the point clicked on the screen
     pub fn ray_from_screen(&self, p: Point2<i32>) -> Ray<Point3<f32>> {
-        let mut v = Vector4::new((2.0*p.x as f32)/self.width - 1.0, 1.0 - (2.0*p.y as f32)/self.height, 1.0, 1.0);
+        let (x, y) = ((2.0*p.x as f32)/self.width - 1.0, 1.0 - (2.0*p.y as f32)/self.height);
+
+        // isometries always have inverses, and so does a perspective with a near plane
+        let unproject = (self.persp.to_matrix()*self.view.to_homogeneous()).inverse().unwrap();
+        let point_at = |depth: f32| {
+            let v = unproject*Vector4::new(x, y, depth, 1.0);
+            Point3::new(v.x/v.w, v.y/v.w, v.z/v.w)
+        };
+
+        Ray::new(self.position, (point_at(1.0) - point_at(-1.0)).normalize())
+    }
+
+    /// Where `p` shows up on screen, in pixels. None if it's behind the camera.
+    pub fn world_to_screen(&self, p: &Point3<f32>) -> Option<Point2<f32>> {
+        let clip = self.persp.to_matrix()*self.view.to_homogeneous()*Vector4::new(p.x, p.y, p.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let (x, y) = (clip.x/clip.w, clip.y/clip.w);
+        Some(Point2::new((x + 1.0)*self.width/2.0, (1.0 - y)*self.height/2.0))
+    }
 
-        // isometries always have inverses. perspective should as well, I think?
-        v = (self.persp.as_matrix().inverse().unwrap() * self.view.inverse().unwrap().to_homogeneous() * v).normalize();
+    /// The bounding rectangle of the ground plane visible on screen. Corners of the screen that
+    /// look above the horizon are left out.
+    pub fn view_area(&self) -> ViewArea {
+        use std::f32;
 
-        Ray::new(self.position, nalgebra::from_homogeneous(&v))
+        let (w, h) = (self.width as i32, self.height as i32);
+        let corners = [Point2::new(0, 0), Point2::new(w, 0), Point2::new(0, h), Point2::new(w, h)];
+
+        let mut min = Point2::new(f32::MAX, f32::MAX);
+        let mut max = Point2::new(f32::MIN, f32::MIN);
+        for ground in corners.iter().filter_map(|&corner| self.ground_at(corner)) {
+            min.x = min.x.min(ground.x);
+            min.y = min.y.min(ground.y);
+            max.x = max.x.max(ground.x);
+            max.y = max.y.max(ground.y);
+        }
+
+        if min.x > max.x {
+            // looking at the sky, just show what's right below
+            let below = Point2::new(self.target.x, self.target.y);
+            return ViewArea::new(below, below);
+        }
+        ViewArea::new(min, max)
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct CursorPosition(pub Point2<i32>);
 
+/// Every entity the player has selected
+#[derive(Clone, Debug)]
+pub struct CurrentSelection(pub HashSet<Entity>);
+
+/// What the player is asking the camera to do, gathered from input each frame.
+#[derive(Clone, Debug)]
+pub struct CameraControl {
+    /// Direction to pan in from the keys held, -1 to 1 on each axis. Positive y is up the screen.
+    pub pan: Vector2<f32>,
+    /// Zoom steps since the camera last moved, positive is in
+    pub zoom: f32,
+    /// Whether the view is being dragged around with the mouse
+    pub dragging: bool,
+    /// Keep the selection in the middle of the view
+    pub follow: bool,
+    /// Whether the window has focus and the cursor is in it, so the edges of the screen scroll
+    /// the view
+    pub focused: bool,
+}
+
+impl CameraControl {
+    pub fn new() -> CameraControl {
+        CameraControl {
+            pan: nalgebra::zero(),
+            zoom: 0.0,
+            dragging: false,
+            follow: false,
+            focused: false,
+        }
+    }
+}
+
+/// Selections saved to the number keys, so they can be picked again with one key press. Members
+/// are kept by their id on the server, so they stay in the group while out of view.
+#[derive(Clone, Debug)]
+pub struct ControlGroups {
+    pub groups: HashMap<u8, HashSet<Entity>>,
+    /// Where members that left the view were last seen
+    pub last_seen: HashMap<Entity, Point3<f32>>,
+}
+
+impl ControlGroups {
+    pub fn new() -> ControlGroups {
+        ControlGroups {
+            groups: HashMap::new(),
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Replace group `number` with `entities`. An empty selection clears the group.
+    pub fn store(&mut self, number: u8, entities: HashSet<Entity>) {
+        if entities.is_empty() {
+            self.groups.remove(&number);
+        }
+        else {
+            self.groups.insert(number, entities);
+        }
+        self.forget_unused();
+    }
+
+    fn contains(&self, entity: Entity) -> bool {
+        self.groups.values().any(|group| group.contains(&entity))
+    }
+
+    fn forget_unused(&mut self) {
+        let groups = &self.groups;
+        self.last_seen.retain(|e, _| groups.values().any(|group| group.contains(e)));
+    }
+
+    /// Remember where an entity was when it left the view, if it's in a group.
+    pub fn left_view(&mut self, entity: Entity, position: Point3<f32>) {
+        if self.contains(entity) {
+            self.last_seen.insert(entity, position);
+        }
+    }
+
+    /// An entity is back in view, so it can be found without remembering where it went.
+    pub fn came_into_view(&mut self, entity: Entity) {
+        self.last_seen.remove(&entity);
+    }
+
+    /// Take an entity that no longer exists out of every group.
+    pub fn remove_entity(&mut self, entity: Entity) {
+        for group in self.groups.values_mut() {
+            group.remove(&entity);
+        }
+        self.groups.retain(|_, group| !group.is_empty());
+        self.last_seen.remove(&entity);
+    }
+}
+
+/// Where the selection rectangle being dragged out starts and ends on screen, in pixels
 #[derive(Clone, Debug)]
-pub struct CurrentSelection(pub Option<Entity>);
+pub struct DragSelection(pub Option<(Point2<i32>, Point2<i32>)>);
 
 #[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
 pub enum CurrentHover {
@@ -0,0 +1,21 @@
+//! Helpers shared by the client and server tests.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// A path in the temp dir that only one test in this process uses, removed when dropped
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(test: &str) -> TempFile {
+        TempFile(env::temp_dir().join(format!("box_specs_{}_{}", process::id(), test)))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,415 @@
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration as StdDuration;
+
+use common::{Message, NetworkMessage};
+use common::net::{generate_keypair, Connection};
+
+/// A connected pair of blocking streams on loopback, (client, server)
+fn loopback_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    for s in &[&client, &server] {
+        s.set_read_timeout(Some(StdDuration::from_millis(500))).unwrap();
+    }
+    (client, server)
+}
+
+fn receive_one(connection: &mut Connection) -> NetworkMessage {
+    for _ in 0..10 {
+        connection.receive().unwrap();
+        if let Some(msg) = connection.next_message() {
+            return msg.unwrap();
+        }
+    }
+    panic!("no message received");
+}
+
+#[test]
+fn plaintext_connection_roundtrip() {
+    let (client, server) = loopback_pair();
+    let mut client = Connection::plaintext(client);
+    let mut server = Connection::plaintext(server);
+
+    client.send(&NetworkMessage::GameMessage(Message::Quit)).unwrap();
+    client.send(&NetworkMessage::Motd("two\nlines".to_owned())).unwrap();
+
+    match receive_one(&mut server) {
+        NetworkMessage::GameMessage(Message::Quit) => (),
+        other => panic!("unexpected message {:?}", other),
+    }
+    match receive_one(&mut server) {
+        NetworkMessage::Motd(motd) => assert_eq!(motd, "two\nlines"),
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn noise_connection_roundtrip() {
+    let (private, public) = generate_keypair().unwrap();
+    let (client, server) = loopback_pair();
+    let mut client = Connection::noise_initiator(client, Some(public)).unwrap();
+    let mut server = Connection::noise_responder(server, &private).unwrap();
+
+    // sent before the handshake is done, so held until it finishes
+    client.send(&NetworkMessage::Motd("hello".to_owned())).unwrap();
+
+    // server reads the first handshake message and answers, client finishes and sends
+    server.receive().unwrap();
+    client.receive().unwrap();
+
+    match receive_one(&mut server) {
+        NetworkMessage::Motd(motd) => assert_eq!(motd, "hello"),
+        other => panic!("unexpected message {:?}", other),
+    }
+
+    // longer than a single noise frame
+    let long = "x".repeat(70000);
+    server.send(&NetworkMessage::Motd(long.clone())).unwrap();
+    match receive_one(&mut client) {
+        NetworkMessage::Motd(motd) => assert_eq!(motd, long),
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn noise_rejects_wrong_server_key() {
+    let (private, _) = generate_keypair().unwrap();
+    let (_, other_public) = generate_keypair().unwrap();
+    let (client, server) = loopback_pair();
+    let mut client = Connection::noise_initiator(client, Some(other_public)).unwrap();
+    let mut server = Connection::noise_responder(server, &private).unwrap();
+
+    server.receive().unwrap();
+    assert!(client.receive().is_err());
+}
+
+#[test]
+fn oversized_message_is_an_error() {
+    let (client, server) = loopback_pair();
+    let mut client = Connection::plaintext(client);
+    let mut server = Connection::plaintext(server);
+    server.set_max_message_size(64);
+
+    client.send(&NetworkMessage::Motd("short".to_owned())).unwrap();
+    match receive_one(&mut server) {
+        NetworkMessage::Motd(motd) => assert_eq!(motd, "short"),
+        other => panic!("unexpected message {:?}", other),
+    }
+
+    client.send(&NetworkMessage::Motd("x".repeat(100))).unwrap();
+    let error = server.receive().unwrap_err();
+    assert_eq!(error.kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn endless_messages_are_cut_off_at_the_size_limit() {
+    use std::io;
+    use common::net::MessageReader;
+
+    // never runs out and never ends the message, so reading everything available never finishes
+    let mut stream = io::repeat(b'x');
+    let mut reader = MessageReader::new();
+    reader.set_max_message_size(100);
+    let error = reader.read_from(&mut stream).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn heartbeat_measures_rtt_and_times_out() {
+    use std::thread;
+    use time::Duration;
+    use common::net::Heartbeat;
+
+    let mut heartbeat = Heartbeat::new(Duration::zero(), Duration::milliseconds(50));
+    assert!(heartbeat.rtt().is_none());
+
+    let sequence = match heartbeat.poll() {
+        Some(NetworkMessage::Ping(s)) => s,
+        other => panic!("expected a ping, got {:?}", other),
+    };
+    heartbeat.pong(sequence + 1); // not the ping we sent
+    assert!(heartbeat.rtt().is_none());
+
+    thread::sleep(StdDuration::from_millis(5));
+    heartbeat.pong(sequence);
+    assert!(heartbeat.rtt().unwrap() >= Duration::milliseconds(5));
+
+    assert!(!heartbeat.timed_out());
+    thread::sleep(StdDuration::from_millis(60));
+    assert!(heartbeat.timed_out());
+    heartbeat.received();
+    assert!(!heartbeat.timed_out());
+}
+
+#[test]
+fn nonblocking_send_queues_instead_of_blocking() {
+    let (client, server) = loopback_pair();
+    client.set_nonblocking(true).unwrap();
+    let mut client = Connection::plaintext(client);
+    let mut server = Connection::plaintext(server);
+
+    // far more than the socket buffers hold while nobody is reading
+    let chunk = "x".repeat(1000);
+    for _ in 0..10000 {
+        client.send(&NetworkMessage::Motd(chunk.clone())).unwrap();
+    }
+    assert!(client.queued_bytes() > 0);
+
+    let mut received = 0;
+    while received < 10000 {
+        server.receive().unwrap();
+        while let Some(msg) = server.next_message() {
+            match msg.unwrap() {
+                NetworkMessage::Motd(motd) => assert_eq!(motd, chunk),
+                other => panic!("unexpected message {:?}", other),
+            }
+            received += 1;
+        }
+        client.flush().unwrap();
+    }
+    assert_eq!(client.queued_bytes(), 0);
+}
+
+#[test]
+fn entity_state_deltas_only_carry_changes() {
+    use nalgebra::Point3;
+    use specs::World;
+    use common::components::{Movement, MovementStats};
+    use common::replication::{EntityState, QuantizedPoint, Replication};
+
+    let mut world = World::new();
+    let e = world.create_now().build();
+
+    let mut stats = MovementStats::default();
+    let mut m = Movement::new_pos_target(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 0.0, 0.0));
+    let baseline = EntityState::new(&m, &stats, true);
+
+    // moving along the same path only changes the position, and less than the quantization
+    // step changes nothing
+    m.position = Point3::new(0.001, 0.0, 0.0);
+    assert!(EntityState::new(&m, &stats, true).delta_from(e, &baseline).is_empty());
+
+    m.advance(&stats, 2500.0);
+    match EntityState::new(&m, &stats, true).delta_from(e, &baseline)[..] {
+        [Replication::Moved(_, position)] => assert_eq!(position, QuantizedPoint(500, 0, 0)),
+        ref other => panic!("unexpected changes {:?}", other),
+    }
+
+    m.set_target(Point3::new(5.0, 5.0, 0.0));
+    stats.max_speed *= 2.0;
+    let current = EntityState::new(&m, &stats, true);
+    let changes = current.delta_from(e, &baseline);
+    assert_eq!(changes.len(), 3);
+
+    let mut applied = baseline.clone();
+    for change in &changes {
+        applied.apply(change);
+    }
+    assert!(current.delta_from(e, &applied).is_empty());
+}
+
+#[test]
+fn movement_follows_queued_waypoints() {
+    use nalgebra::{self, Point3};
+    use common::components::{Movement, MovementStats};
+
+    let close = |a: Point3<f32>, b: Point3<f32>| nalgebra::distance(&a, &b) < 1.0e-4;
+    let stats = MovementStats::default();
+
+    let mut m = Movement::new_pos_target(Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0));
+    m.queue_target(Point3::new(2.0, 2.0, 0.0));
+
+    // waypoints along the way are passed through without stopping
+    while m.path.waypoints.len() == 2 {
+        m.advance(&stats, 16.0);
+    }
+    assert!(close(m.position, Point3::new(2.0, 0.0, 0.0)));
+    assert!(m.speed() > 0.0);
+
+    for _ in 0..1000 {
+        m.advance(&stats, 16.0);
+    }
+    assert!(close(m.position, Point3::new(2.0, 2.0, 0.0)));
+    assert!(m.path.is_empty());
+    assert_eq!(m.speed(), 0.0);
+
+    // a new target replaces the queue
+    m.queue_target(Point3::new(0.0, 2.0, 0.0));
+    m.set_target(Point3::new(2.0, 0.0, 0.0));
+    assert_eq!(m.path.waypoints.len(), 1);
+
+    // already there
+    m.set_target(m.position);
+    m.advance(&stats, 16.0);
+    assert!(m.path.is_empty());
+    assert!(close(m.position, Point3::new(2.0, 2.0, 0.0)));
+    assert!(!m.position.x.is_nan() && m.speed() == 0.0);
+}
+
+#[test]
+fn movement_arrives_in_time_for_its_speed() {
+    use nalgebra::Point3;
+    use common::components::{Movement, MovementStats};
+
+    // how long it takes to get `distance` from a standstill, speeding up and slowing down as
+    // quickly as we can, with continuous time
+    let expected = |stats: &MovementStats, distance: f32| {
+        let (v, a, b) = (stats.max_speed, stats.acceleration, stats.deceleration);
+        let ramps = v*v/(2.0*a) + v*v/(2.0*b);
+        if ramps <= distance {
+            v/a + v/b + (distance - ramps)/v
+        }
+        else {
+            let peak = (2.0*distance/(1.0/a + 1.0/b)).sqrt();
+            peak/a + peak/b
+        }
+    };
+
+    // the default stats, and something slow and sluggish
+    let slow = MovementStats::new(0.0005, 0.000001, 0.000004, 0.00314);
+    for stats in &[MovementStats::default(), slow] {
+        for &distance in &[0.1, 10.0, 40.0] {
+            for &step in &[1.0, 16.0, 50.0] {
+                let mut m = Movement::new_pos_target(Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, distance, 0.0));
+                let mut elapsed = 0.0;
+                while !m.path.is_empty() {
+                    m.advance(stats, step);
+                    elapsed += step;
+                    assert!(m.speed() <= stats.max_speed*1.0001);
+                    assert!(elapsed < 200000.0, "never arrived");
+                }
+                assert_eq!(m.position, Point3::new(0.0, distance, 0.0));
+
+                let estimate = expected(stats, distance);
+                assert!((elapsed - estimate).abs() <= estimate*0.05 + 2.0*step,
+                        "{} units in {}ms steps took {}ms, expected about {}ms", distance, step, elapsed, estimate);
+            }
+        }
+    }
+
+    // a turn around slows us down first rather than circling
+    let stats = MovementStats::default();
+    let mut m = Movement::new_pos_target(Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 0.0, 0.0));
+    for _ in 0..100 {
+        m.advance(&stats, 16.0);
+    }
+    m.set_target(Point3::new(0.0, 0.0, 0.0));
+    let mut elapsed = 0.0;
+    while !m.path.is_empty() {
+        m.advance(&stats, 16.0);
+        elapsed += 16.0;
+        assert!(elapsed < 20000.0, "never turned around");
+    }
+}
+
+#[test]
+fn steering_is_eased_in_and_never_too_fast() {
+    use nalgebra::{Norm, Point3, Vector3};
+    use common::components::{Movement, MovementStats, Steering};
+
+    let stats = MovementStats::default();
+    let mut m = Movement::new_pos_target(Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 40.0, 0.0));
+    let mut steering = Steering::new();
+    steering.desired = Vector3::new(stats.max_speed, 0.0, 0.0);
+
+    m.advance_steered(&stats, &mut steering, 16.0);
+    assert!(steering.current.norm() <= stats.acceleration*16.0*1.0001);
+
+    for _ in 0..200 {
+        m.advance_steered(&stats, &mut steering, 16.0);
+        assert!(m.speed() <= stats.max_speed*1.0001);
+    }
+    // pushed off to the side, but still making progress
+    assert!(m.position.x > 0.0);
+    assert!(m.position.y > 0.0);
+
+    // and the path still gets us there once the steering lets up
+    steering.desired = Vector3::new(0.0, 0.0, 0.0);
+    let mut elapsed = 0.0;
+    while !m.path.is_empty() {
+        m.advance_steered(&stats, &mut steering, 16.0);
+        elapsed += 16.0;
+        assert!(elapsed < 60000.0, "never arrived");
+    }
+    assert_eq!(m.position, Point3::new(0.0, 40.0, 0.0));
+    assert_eq!(steering.current, Vector3::new(0.0, 0.0, 0.0));
+}
+
+#[test]
+fn control_groups_forget_destroyed_entities() {
+    use specs::World;
+    use nalgebra::Point3;
+    use common::resources::ControlGroups;
+
+    let mut world = World::new();
+    let a = world.create_now().build();
+    let b = world.create_now().build();
+
+    let mut groups = ControlGroups::new();
+    groups.store(1, vec![a, b].into_iter().collect());
+    groups.store(2, vec![b].into_iter().collect());
+
+    // leaving the view keeps an entity in its groups, and remembers where it went
+    groups.left_view(b, Point3::new(1.0, 2.0, 0.0));
+    assert!(groups.groups[&2].contains(&b));
+    assert_eq!(groups.last_seen[&b], Point3::new(1.0, 2.0, 0.0));
+    groups.came_into_view(b);
+    assert!(groups.last_seen.is_empty());
+    groups.left_view(b, Point3::new(1.0, 2.0, 0.0));
+
+    groups.remove_entity(b);
+    assert_eq!(groups.groups[&1].len(), 1);
+    assert!(groups.groups[&1].contains(&a));
+    assert!(!groups.groups.contains_key(&2));
+    assert!(groups.last_seen.is_empty());
+
+    // storing nothing clears the group
+    groups.store(1, Default::default());
+    assert!(groups.groups.is_empty());
+}
+
+#[test]
+fn screen_rays_hit_what_is_drawn_there() {
+    use std::f32::consts::FRAC_PI_4;
+    use nalgebra::{self, Norm, Point2, Point3};
+    use common::resources::Camera;
+
+    let mut camera = Camera::new(1280, 720, FRAC_PI_4);
+    let check = |camera: &Camera| {
+        let size = camera.screen_size();
+        let center = Point2::new((size.x/2.0) as i32, (size.y/2.0) as i32);
+        let ray = camera.ray_from_screen(center);
+        assert!((ray.dir.norm() - 1.0).abs() < 1.0e-4);
+        assert!(nalgebra::distance(&camera.ground_at(center).unwrap(), &camera.target) < 0.1);
+
+        // the edges of the screen are as far out as the field of view and aspect ratio say
+        let half_height = camera.distance()*(FRAC_PI_4/2.0).tan();
+        let half_width = half_height*size.x/size.y;
+        let right = camera.ground_at(Point2::new(size.x as i32, center.y)).unwrap();
+        let top = camera.ground_at(Point2::new(center.x, 0)).unwrap();
+        assert!((right.x - camera.target.x - half_width).abs() < 0.1, "{:?} for {:?}", right, size);
+        assert!((top.y - camera.target.y - half_height).abs() < 0.1, "{:?} for {:?}", top, size);
+
+        // picking finds whatever shows up under the cursor
+        for p in &[Point3::new(5.0, -3.0, 0.0), Point3::new(-12.0, 8.0, 0.0), Point3::new(0.5, 0.5, 0.0)] {
+            let on_screen = camera.world_to_screen(p).unwrap();
+            let picked = camera.ground_at(Point2::new(on_screen.x.round() as i32, on_screen.y.round() as i32)).unwrap();
+            assert!(nalgebra::distance(&picked, p) < 0.1, "{:?} picked as {:?} for {:?}", p, picked, size);
+        }
+    };
+
+    check(&camera);
+    camera.set_screen_size(720, 1280);
+    check(&camera);
+    camera.set_screen_size(800, 800);
+    check(&camera);
+
+    // looking at an angle, only the round trip holds
+    camera.move_to(Point3::new(0.0, -30.0, 40.0));
+    for p in &[Point3::new(5.0, -3.0, 0.0), Point3::new(-8.0, 6.0, 0.0)] {
+        let on_screen = camera.world_to_screen(p).unwrap();
+        let picked = camera.ground_at(Point2::new(on_screen.x.round() as i32, on_screen.y.round() as i32)).unwrap();
+        assert!(nalgebra::distance(&picked, p) < 0.2, "{:?} picked as {:?}", p, picked);
+    }
+}
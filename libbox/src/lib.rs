@@ -6,6 +6,7 @@ extern crate nalgebra;
 extern crate ncollide;
 
 extern crate rustc_serialize;
+extern crate snow;
 
 mod common;
 #[cfg(feature = "client")]
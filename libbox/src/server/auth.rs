@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Decides whether a player may join, given the name and token from their `ConnectRequest`.
+pub trait Authenticator: Send {
+    fn authenticate(&self, name: &str, token: Option<&str>) -> bool;
+}
+
+/// Lets anyone in under any name.
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _: &str, _: Option<&str>) -> bool {
+        true
+    }
+}
+
+/// Lets in anyone who knows the server password.
+pub struct StaticPassword(pub String);
+
+impl Authenticator for StaticPassword {
+    fn authenticate(&self, _: &str, token: Option<&str>) -> bool {
+        token == Some(self.0.as_str())
+    }
+}
+
+/// Only lets in known players, each with their own password.
+pub struct UserList {
+    users: HashMap<String, String>,
+}
+
+impl UserList {
+    /// Load a user list from a file with one `name password` pair per line. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<UserList> {
+        let file = BufReader::new(File::open(path)?);
+
+        let mut users = HashMap::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(password)) => {
+                    users.insert(name.to_owned(), password.trim().to_owned());
+                }
+                _ => {
+                    let msg = format!("{:?} line {}: expected `name password`", path, i + 1);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+
+        Ok(UserList {
+            users: users,
+        })
+    }
+}
+
+impl Authenticator for UserList {
+    fn authenticate(&self, name: &str, token: Option<&str>) -> bool {
+        match (self.users.get(name), token) {
+            (Some(password), Some(token)) => password == token,
+            _ => false,
+        }
+    }
+}
+
+/// Which `Authenticator` the server uses.
+#[derive(Clone, Debug)]
+pub enum AuthConfig {
+    AllowAll,
+    Password(String),
+    UserFile(PathBuf),
+}
+
+impl AuthConfig {
+    pub fn build(&self) -> io::Result<Box<dyn Authenticator>> {
+        match *self {
+            AuthConfig::AllowAll => Ok(Box::new(AllowAll)),
+            AuthConfig::Password(ref password) => Ok(Box::new(StaticPassword(password.clone()))),
+            AuthConfig::UserFile(ref path) => Ok(Box::new(UserList::load(path)?)),
+        }
+    }
+}
@@ -0,0 +1,668 @@
+//! Client connections, handled on their own thread so socket work never holds up the
+//! simulation. The `NetworkSystem` talks to this thread through a pair of channels.
+
+use std::collections::HashMap;
+
+use std::io;
+
+use std::mem;
+
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+
+use std::num::Wrapping;
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+
+use std::thread;
+
+use std::time::Duration as StdDuration;
+
+use time::{Duration, PreciseTime};
+
+use rustc_serialize::hex::ToHex;
+
+use server::ServerConfig;
+use server::Authenticator;
+use server::motd::Motd;
+use server::ratelimit::ClientLimits;
+
+use common::{Capabilities, ChatMessage, DisconnectReason, Message, NetworkMessage, ProtocolRange, RejectReason, ServerInfo, Version, ViewArea, Welcome};
+use common::discovery::DiscoveryResponder;
+use common::net::{generate_keypair, Connection, Heartbeat, ServerEncryption};
+use common::resources::ClientStats;
+
+/// Sent from the network thread to the simulation.
+#[derive(Debug)]
+pub enum NetworkEvent {
+    /// A client finished the handshake as the named player
+    Connected(u16, String),
+    Disconnected(u16),
+    /// A game command from a connected client
+    Command(u16, Message),
+    /// The area a client that supports replication is looking at
+    View(u16, ViewArea),
+    /// A client has applied the snapshot with this sequence number
+    AckSnapshot(u16, u32),
+    /// Statistics for every open connection, sent every `connection_stats_interval`
+    Stats(HashMap<u16, ClientStats>),
+}
+
+/// Sent from the simulation to the network thread.
+#[derive(Debug)]
+pub enum NetworkCommand {
+    /// Send a message to a connected client
+    Send(u16, NetworkMessage),
+    /// Disconnect everyone, stop listening and end the thread
+    Shutdown,
+}
+
+struct ClientConnection {
+    pub connection: Connection,
+    pub client_id: u16,
+    /// Whether the client has completed the handshake, as opposed to e.g. just querying info
+    pub connected: bool,
+    /// The authenticated player name, set once connected
+    pub name: String,
+    /// Negotiated during the handshake
+    pub protocol: u32,
+    pub capabilities: Capabilities,
+    pub limits: ClientLimits,
+    pub stats: ClientStats,
+    pub heartbeat: Heartbeat,
+    /// When the client was told it's being disconnected, if it has been
+    pub closed_at: Option<PreciseTime>,
+}
+
+impl ClientConnection {
+    pub fn new(connection: Connection, client_id: u16, limits: ClientLimits, heartbeat: Heartbeat) -> ClientConnection {
+        ClientConnection {
+            connection: connection,
+            client_id: client_id,
+            connected: false,
+            name: String::new(),
+            protocol: 0,
+            capabilities: Capabilities::empty(),
+            limits: limits,
+            stats: ClientStats::default(),
+            heartbeat: heartbeat,
+            closed_at: None,
+        }
+    }
+
+    pub fn send(&mut self, msg: &NetworkMessage) -> io::Result<()> {
+        self.connection.send(msg)
+    }
+
+    /// Queue one last message. Once the client is removed, the socket is closed as soon as the
+    /// message has gone out, or after `CLOSE_TIMEOUT_MS` if the client isn't reading.
+    pub fn close_with(&mut self, msg: &NetworkMessage) {
+        // the client may already be gone, in which case there's nobody to tell
+        let _ = self.send(msg);
+        self.closed_at = Some(PreciseTime::now());
+    }
+
+    /// Tell the client why it is being dropped, and close the socket once it has been told.
+    pub fn disconnect(&mut self, reason: &str) {
+        self.close_with(&NetworkMessage::Disconnect(DisconnectReason(reason.to_owned())));
+    }
+
+    /// Record that the client broke a limit. If it has done so too often, disconnect it and
+    /// return true.
+    pub fn violation(&mut self, reason: &str) -> bool {
+        self.stats.violations += 1;
+        println!("client {} ({}) is {}", self.client_id, self.name, reason);
+        if self.limits.violations.try_take(1.0) {
+            return false;
+        }
+
+        println!("disconnecting client {} ({}) after {} violations", self.client_id, self.name, self.stats.violations);
+        self.disconnect(&format!("disconnected for {}", reason));
+        true
+    }
+}
+
+pub struct NetworkServer {
+    connected_clients: Vec<ClientConnection>, // hashmap may be better
+    /// Removed clients still being sent the reason why
+    closing: Vec<ClientConnection>,
+    /// Names of the clients that have finished the handshake
+    players: HashMap<u16, String>,
+    events: Sender<NetworkEvent>,
+    listener: Option<TcpListener>,
+    current_id: Wrapping<u16>,
+    info: ServerInfo,
+    motd: Motd,
+    start_time: PreciseTime,
+    discovery: Option<DiscoveryResponder>,
+    authenticator: Box<dyn Authenticator>,
+    /// Static private key for encrypted connections, or None for plaintext
+    noise_key: Option<Vec<u8>>,
+    max_chat_length: usize,
+    max_message_size: usize,
+    limits: ClientLimits,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    max_queued_bytes: usize,
+    poll_interval: StdDuration,
+    stats_interval: Duration,
+    last_stats: PreciseTime,
+}
+
+const MAX_NAME_LENGTH: usize = 32;
+
+/// How long a disconnected client has to read its last message before the socket is closed anyway
+const CLOSE_TIMEOUT_MS: i64 = 500;
+
+fn valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.chars().count() <= MAX_NAME_LENGTH && !name.chars().any(char::is_control)
+}
+
+impl NetworkServer {
+    pub fn new(cfg: ServerConfig, events: Sender<NetworkEvent>) -> NetworkServer {
+        let listener = TcpListener::bind(cfg.server_address).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let discovery = cfg.discovery_port.and_then(|port| {
+            DiscoveryResponder::new(port)
+                .map_err(|error| println!("LAN discovery disabled, could not bind port {}: {:?}", port, error))
+                .ok()
+        });
+
+        let authenticator = cfg.auth.build().expect("Failed to set up authentication");
+
+        let noise_key = match cfg.encryption {
+            ServerEncryption::Plaintext => None,
+            ServerEncryption::Noise(Some(key)) => Some(key),
+            ServerEncryption::Noise(None) => {
+                let (private, public) = generate_keypair().expect("Failed to generate server key");
+                println!("generated server key, clients can pin it with --server-key {}", public.to_hex());
+                Some(private)
+            }
+        };
+
+        let info = ServerInfo {
+            name: cfg.name,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocols: ProtocolRange::supported(),
+            player_count: 0,
+            capacity: cfg.max_clients,
+            uptime_secs: 0,
+        };
+
+        NetworkServer {
+            connected_clients: Vec::new(),
+            closing: Vec::new(),
+            players: HashMap::new(),
+            events: events,
+            listener: Some(listener),
+            current_id: Wrapping(0u16),
+            info: info,
+            motd: Motd::new(cfg.motd, cfg.motd_file),
+            start_time: PreciseTime::now(),
+            discovery: discovery,
+            authenticator: authenticator,
+            noise_key: noise_key,
+            max_chat_length: cfg.max_chat_length,
+            max_message_size: cfg.max_message_size,
+            limits: ClientLimits::new(&cfg),
+            heartbeat_interval: cfg.heartbeat_interval,
+            idle_timeout: cfg.idle_timeout,
+            max_queued_bytes: cfg.max_queued_bytes,
+            poll_interval: cfg.network_poll_interval.to_std().unwrap(),
+            stats_interval: cfg.connection_stats_interval,
+            last_stats: PreciseTime::now(),
+        }
+    }
+
+    /// The address clients connect to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.listener {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not listening")),
+        }
+    }
+
+    /// Service connections until told to shut down or the simulation goes away.
+    pub fn run(mut self, commands: Receiver<NetworkCommand>) {
+        let mut last = PreciseTime::now();
+        'running: loop {
+            // wait for something to send, or until it's time to poll the sockets again
+            let mut pending = match commands.recv_timeout(self.poll_interval) {
+                Ok(command) => vec![command],
+                Err(RecvTimeoutError::Timeout) => Vec::new(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            pending.extend(commands.try_iter());
+
+            for command in pending {
+                match command {
+                    NetworkCommand::Send(id, msg) => self.send_to(id, &msg),
+                    NetworkCommand::Shutdown => break 'running,
+                }
+            }
+
+            let now = PreciseTime::now();
+            let dt = last.to(now);
+            last = now;
+
+            if self.poll(dt).is_err() {
+                break; // nobody is listening for events anymore
+            }
+        }
+        self.shutdown();
+    }
+
+    fn poll(&mut self, dt: Duration) -> Result<(), ()> {
+        for client in &mut self.connected_clients {
+            client.limits.refill(dt);
+        }
+
+        self.handle_incoming_connections();
+        self.update_info();
+        self.handle_incoming_messages();
+        self.handle_heartbeats();
+        self.flush_clients();
+        self.flush_closing();
+        self.handle_discovery();
+
+        let now = PreciseTime::now();
+        if self.last_stats.to(now) < self.stats_interval {
+            return Ok(());
+        }
+        self.last_stats = now;
+        let stats = self.connected_clients.iter().map(|c| (c.client_id, c.stats.clone())).collect();
+        self.events.send(NetworkEvent::Stats(stats)).map_err(|_| ())
+    }
+
+    fn send_to(&mut self, client_id: u16, msg: &NetworkMessage) {
+        if let Some(client) = self.connected_clients.iter_mut().find(|c| c.client_id == client_id && c.connected) {
+            // errors show up on the next receive or flush
+            let _ = client.send(msg);
+        }
+    }
+
+    /// Forget the given clients. Ones that were told why are kept around until it's sent, the
+    /// rest are closed straight away.
+    fn remove_clients(&mut self, disconnected: &[u16]) {
+        for id in disconnected {
+            if self.players.remove(id).is_some() {
+                let _ = self.events.send(NetworkEvent::Disconnected(*id));
+            }
+        }
+        let clients = mem::replace(&mut self.connected_clients, Vec::new());
+        let (removed, remaining): (Vec<_>, Vec<_>) = clients.into_iter().partition(|c| disconnected.contains(&c.client_id));
+        self.connected_clients = remaining;
+        self.closing.extend(removed.into_iter().filter(|c| c.closed_at.is_some()));
+    }
+
+    /// Close the sockets of removed clients once their last message has gone out, or they've had
+    /// long enough to read it.
+    fn flush_closing(&mut self) {
+        let now = PreciseTime::now();
+        let timeout = Duration::milliseconds(CLOSE_TIMEOUT_MS);
+        let closing = mem::replace(&mut self.closing, Vec::new());
+        for mut client in closing {
+            let sent = match client.connection.flush() {
+                Ok(()) => client.connection.queued_bytes() == 0,
+                Err(_) => true, // nobody left to send to
+            };
+            let expired = client.closed_at.map_or(true, |at| at.to(now) > timeout);
+            if sent || expired {
+                let _ = client.connection.stream().shutdown(Shutdown::Both);
+            }
+            else {
+                self.closing.push(client);
+            }
+        }
+    }
+
+    fn update_info(&mut self) {
+        self.info.player_count = self.connected_clients.iter().filter(|c| c.connected).count() as u16;
+        self.info.uptime_secs = self.start_time.to(PreciseTime::now()).num_seconds() as u64;
+    }
+
+    fn handle_discovery(&mut self) {
+        let game_address = match self.listener {
+            Some(ref listener) => listener.local_addr().unwrap(),
+            None => return, // shut down, don't advertise
+        };
+        if let Some(ref discovery) = self.discovery {
+            discovery.respond(&self.info, game_address);
+        }
+    }
+
+    fn handle_new_connection(&mut self, stream: TcpStream) {
+        stream.set_nodelay(true).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let connection = match self.noise_key {
+            Some(ref key) => Connection::noise_responder(stream, key),
+            None => Ok(Connection::plaintext(stream)),
+        };
+        let mut connection = match connection {
+            Ok(c) => c,
+            Err(error) => {
+                println!("failed to set up connection: {:?}", error);
+                return;
+            }
+        };
+
+        // TODO do a search for unused client id and err if we don't have one
+        // but actually we should just reject new connections if we are full
+        connection.set_max_message_size(self.max_message_size);
+        let heartbeat = Heartbeat::new(self.heartbeat_interval, self.idle_timeout);
+        let client = ClientConnection::new(connection, self.current_id.0, self.limits.clone(), heartbeat);
+        self.connected_clients.push(client);
+        self.current_id += Wrapping(1);
+    }
+
+    fn handle_incoming_connections(&mut self) {
+        loop {
+            let stream = match self.listener {
+                Some(ref listener) => listener.accept(),
+                None => return, // shut down
+            };
+            match stream {
+                Ok(s) => {
+                    self.handle_new_connection(s.0);
+                }
+                Err(_) => {
+                    break;
+                    // don't know what to do here yet.
+                }
+            }
+        }
+    }
+
+    /// Disconnect every client and stop accepting new connections.
+    fn shutdown(&mut self) {
+        for client in &mut self.connected_clients {
+            println!("disconnecting client {}", client.client_id);
+            client.disconnect("server shutting down");
+        }
+        let clients = mem::replace(&mut self.connected_clients, Vec::new());
+        self.closing.extend(clients);
+        self.players.clear();
+        self.listener = None;
+        self.discovery = None;
+
+        // nothing else to do on this thread, so wait for everyone to hear why
+        while !self.closing.is_empty() {
+            self.flush_closing();
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    fn handle_incoming_messages(&mut self) {
+        let mut disconnected = Vec::new();
+        let mut chat = Vec::new();
+        for client in &mut self.connected_clients {
+            let bytes = match client.connection.receive() {
+                Ok(n) => n,
+                Err(error) => {
+                    if error.kind() == io::ErrorKind::InvalidData {
+                        // oversized message or bad encryption, we can't make sense of anything
+                        // after this
+                        println!("disconnecting client {} ({}): {}", client.client_id, client.name, error);
+                        client.disconnect(&error.to_string());
+                    }
+                    else {
+                        println!("client {} ({}) connection lost: {:?}", client.client_id, client.name, error);
+                    }
+                    disconnected.push(client.client_id);
+                    continue;
+                }
+            };
+
+            if bytes > 0 {
+                client.heartbeat.received();
+            }
+            client.stats.bytes_received += bytes as u64;
+            // the bytes have been read either way, so they count against the limit either way
+            if !client.limits.bytes.charge(bytes as f32) && client.violation("sending too much data") {
+                disconnected.push(client.client_id);
+                continue;
+            }
+
+            while let Some(msg) = client.connection.next_message() {
+                client.stats.messages_received += 1;
+                if !client.limits.messages.try_take(1.0) {
+                    client.stats.dropped_messages += 1;
+                    if client.violation("sending too many messages") {
+                        disconnected.push(client.client_id);
+                        break;
+                    }
+                    continue;
+                }
+
+                let msg = match msg {
+                    Ok(m) => m,
+                    Err(_) => {
+                        // don't print the message, it could be huge or garbage
+                        client.stats.malformed_messages += 1;
+                        if client.violation("sending malformed messages") {
+                            disconnected.push(client.client_id);
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // TODO lots of validation here, and attach client id to messages somehow
+                use common::NetworkMessage::*;
+                match msg {
+                    GameMessage(message) => {
+                        if !client.connected {
+                            continue;
+                        }
+                        // only pass on the commands players are allowed to give
+                        match message {
+                            Message::InteractWith(..) | Message::QueueInteraction(..) | Message::CommandGroup(..) => {
+                                let _ = self.events.send(NetworkEvent::Command(client.client_id, message));
+                            },
+                            _ => (),
+                        }
+                    },
+                    View(area) => {
+                        if !client.connected || !client.capabilities.contains(Capabilities::REPLICATION) {
+                            continue;
+                        }
+                        if !area.is_finite() {
+                            client.stats.malformed_messages += 1;
+                            if client.violation("sending malformed messages") {
+                                disconnected.push(client.client_id);
+                                break;
+                            }
+                            continue;
+                        }
+                        let _ = self.events.send(NetworkEvent::View(client.client_id, area));
+                    },
+                    AckSnapshot(sequence) => {
+                        if client.connected && client.capabilities.contains(Capabilities::REPLICATION) {
+                            let _ = self.events.send(NetworkEvent::AckSnapshot(client.client_id, sequence));
+                        }
+                    },
+                    Connect(request) => {
+                        if client.connected {
+                            continue;
+                        }
+
+                        let supported = ProtocolRange::supported();
+                        let protocol = match supported.best_common(&request.protocols) {
+                            Some(p) => p,
+                            None => {
+                                println!("client {} (version {}) speaks protocols {:?}, we support {:?}",
+                                         client.client_id, request.version.0, request.protocols, supported);
+                                client.close_with(&Rejected(RejectReason::Version(supported, request.protocols)));
+                                disconnected.push(client.client_id);
+                                break;
+                            }
+                        };
+
+                        let rejection = if self.info.player_count >= self.info.capacity {
+                            Some(RejectReason::ServerFull)
+                        }
+                        else if !valid_name(&request.name) {
+                            Some(RejectReason::InvalidName)
+                        }
+                        else if self.players.values().any(|name| *name == request.name) {
+                            Some(RejectReason::NameTaken)
+                        }
+                        else if !self.authenticator.authenticate(&request.name, request.token.as_ref().map(|t| t.as_str())) {
+                            Some(RejectReason::AuthenticationFailed)
+                        }
+                        else {
+                            None
+                        };
+                        if let Some(reason) = rejection {
+                            println!("rejecting client {} ({:?}): {:?}", client.client_id, request.name, reason);
+                            client.close_with(&Rejected(reason));
+                            disconnected.push(client.client_id);
+                            break;
+                        }
+
+                        client.protocol = protocol;
+                        client.capabilities = request.capabilities.intersection(Capabilities::supported());
+                        let welcome = Welcome {
+                            version: Version(env!("CARGO_PKG_VERSION").to_owned()),
+                            protocol: client.protocol,
+                            capabilities: client.capabilities,
+                        };
+                        let mut result = client.send(&Accepted(welcome));
+
+                        if result.is_ok() && client.capabilities.contains(Capabilities::MOTD) {
+                            println!("sending motd to client {}", client.client_id);
+                            let motd = self.motd.current().to_owned();
+                            result = client.send(&Motd(motd));
+                        }
+
+                        if let Err(error) = result {
+                            println!("client {} connection lost: {:?}", client.client_id, error);
+                            disconnected.push(client.client_id);
+                            break;
+                        }
+                        println!("client {} authenticated as {}", client.client_id, request.name);
+                        client.connected = true;
+                        client.name = request.name.clone();
+                        self.players.insert(client.client_id, request.name.clone());
+                        let _ = self.events.send(NetworkEvent::Connected(client.client_id, request.name));
+                        self.info.player_count += 1;
+                    },
+                    ServerInfoRequest => {
+                        // a query doesn't need to stay connected, and closing lets the querier just
+                        // read until eof
+                        if !client.connected {
+                            client.close_with(&ServerInfoResponse(self.info.clone()));
+                            disconnected.push(client.client_id);
+                            break;
+                        }
+                        let _ = client.send(&ServerInfoResponse(self.info.clone()));
+                    },
+                    SendChat(text) => {
+                        if !client.connected || !client.capabilities.contains(Capabilities::CHAT) {
+                            continue;
+                        }
+
+                        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+                        if text.trim().is_empty() {
+                            continue;
+                        }
+
+                        let notice = if text.chars().count() > self.max_chat_length {
+                            Some(format!("chat messages can be at most {} characters", self.max_chat_length))
+                        }
+                        else if !client.limits.chat.try_take(1.0) {
+                            Some("you are sending chat messages too quickly".to_owned())
+                        }
+                        else {
+                            None
+                        };
+
+                        match notice {
+                            Some(notice) => {
+                                let _ = client.send(&Chat(ChatMessage { from: None, text: notice }));
+                            }
+                            None => {
+                                println!("[chat] {}: {}", client.name, text);
+                                chat.push(ChatMessage { from: Some(client.name.clone()), text: text });
+                            }
+                        }
+                    },
+                    Ping(sequence) => {
+                        let _ = client.send(&Pong(sequence));
+                    },
+                    Pong(sequence) => client.heartbeat.pong(sequence),
+                    Accepted(_) | Rejected(_) | Motd(_) | ServerInfoResponse(_) | Chat(_) | Replicate(_) => (), // only sent by server
+                    Disconnect(reason) => {
+                        println!("client {} ({}) disconnected: {}", client.client_id, client.name, reason.0);
+                        disconnected.push(client.client_id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.remove_clients(&disconnected);
+
+        for message in chat {
+            self.broadcast_chat(message);
+        }
+    }
+
+    /// Ping clients that are due one and drop the ones that have gone quiet.
+    fn handle_heartbeats(&mut self) {
+        let mut disconnected = Vec::new();
+        for client in &mut self.connected_clients {
+            // clients without heartbeats may legitimately go quiet, but everyone has to finish
+            // the handshake in time
+            let heartbeats = client.capabilities.contains(Capabilities::HEARTBEAT);
+            if (heartbeats || !client.connected) && client.heartbeat.timed_out() {
+                println!("client {} ({}) timed out", client.client_id, client.name);
+                client.disconnect("timed out");
+                disconnected.push(client.client_id);
+                continue;
+            }
+
+            if heartbeats {
+                if let Some(ping) = client.heartbeat.poll() {
+                    let _ = client.send(&ping);
+                }
+                client.stats.rtt = client.heartbeat.rtt();
+            }
+        }
+
+        self.remove_clients(&disconnected);
+    }
+
+    /// Send whatever the sockets will take of each client's outgoing queue, and drop clients that
+    /// have fallen too far behind.
+    fn flush_clients(&mut self) {
+        let mut disconnected = Vec::new();
+        for client in &mut self.connected_clients {
+            if let Err(error) = client.connection.flush() {
+                println!("client {} ({}) connection lost: {:?}", client.client_id, client.name, error);
+                disconnected.push(client.client_id);
+                continue;
+            }
+
+            let queued = client.connection.queued_bytes();
+            client.stats.bytes_queued = queued as u64;
+            if queued > self.max_queued_bytes {
+                // no point queueing a disconnect message behind everything else
+                println!("client {} ({}) is {} bytes behind, disconnecting", client.client_id, client.name, queued);
+                let _ = client.connection.stream().shutdown(Shutdown::Both);
+                disconnected.push(client.client_id);
+            }
+        }
+
+        self.remove_clients(&disconnected);
+    }
+
+    fn broadcast_chat(&mut self, message: ChatMessage) {
+        let message = NetworkMessage::Chat(message);
+        for client in &mut self.connected_clients {
+            if client.connected && client.capabilities.contains(Capabilities::CHAT) {
+                let _ = client.send(&message);
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Spatial lookup for deciding which entities each client should be told about.
+
+use std::collections::HashMap;
+
+use nalgebra::Point3;
+
+use common::ViewArea;
+
+/// A uniform grid over the ground plane. Finding everything near a view only looks at the cells
+/// the view overlaps, so the cost doesn't grow with the size of the map.
+pub struct SpatialGrid<T> {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<(T, Point3<f32>)>>,
+}
+
+impl<T: Copy> SpatialGrid<T> {
+    pub fn new(cell_size: f32) -> SpatialGrid<T> {
+        SpatialGrid {
+            cell_size: cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        ((x/self.cell_size).floor() as i32, (y/self.cell_size).floor() as i32)
+    }
+
+    pub fn insert(&mut self, item: T, position: Point3<f32>) {
+        let cell = self.cell(position.x, position.y);
+        self.cells.entry(cell).or_insert_with(Vec::new).push((item, position));
+    }
+
+    /// Everything inside `area` grown by `margin` on every side.
+    pub fn query(&self, area: &ViewArea, margin: f32) -> Vec<T> {
+        let (min_x, min_y) = self.cell(area.min.x - margin, area.min.y - margin);
+        let (max_x, max_y) = self.cell(area.max.x + margin, area.max.y + margin);
+
+        let mut found = Vec::new();
+        for x in min_x..max_x+1 {
+            for y in min_y..max_y+1 {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    found.extend(cell.iter().filter(|&&(_, ref p)| area.contains(p, margin)).map(|&(item, _)| item));
+                }
+            }
+        }
+        found
+    }
+}
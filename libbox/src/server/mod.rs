@@ -1,21 +1,111 @@
+use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
 use time::Duration;
 
 use specs;
 
+use nalgebra::{Point2, Point3};
+
 mod systems;
 use self::systems::*;
 
+mod auth;
+pub use self::auth::*;
+
+mod connections;
+mod interest;
+mod navigation;
+
+mod motd;
+mod ratelimit;
+mod save;
+pub use self::save::{load_world, save_world};
+
 use common::Message;
+use common::discovery::DISCOVERY_PORT;
+pub use common::net::{decode_key, ServerEncryption};
 use common::resources::*;
 use common::components::*;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct ServerConfig {
     pub timestep: Duration,
     pub sim_rate: Duration,
+    /// Where to accept game connections. Only loopback by default, and LAN discovery only
+    /// advertises the server to whoever could connect
     pub server_address: SocketAddr,
+    pub name: String,
+    pub max_clients: u16,
+    pub motd: String,
+    /// If set, the motd is read from this file and reloaded when it changes
+    pub motd_file: Option<PathBuf>,
+    /// If set, the world starts with the entities in this file, in the format `save_world` writes
+    pub scenario: Option<PathBuf>,
+    /// Names of the players who own the boxes in the world used when there's no scenario
+    pub default_players: Vec<String>,
+    /// Udp port to answer LAN discovery queries on, or None to not be discoverable
+    pub discovery_port: Option<u16>,
+    pub auth: AuthConfig,
+    pub encryption: ServerEncryption,
+    /// Longest chat message accepted, in characters
+    pub max_chat_length: usize,
+    /// Each client may send this many chat messages in a burst,
+    pub chat_burst: u32,
+    /// refilled at this many messages per second
+    pub chat_rate: f32,
+    /// Largest single message accepted from a client, in bytes
+    pub max_message_size: usize,
+    /// Sustained receive limits per client. Bursts of up to a second's worth are allowed.
+    pub max_bytes_per_sec: u32,
+    pub max_messages_per_sec: u32,
+    /// Each client may break the limits above this many times in a burst before being
+    /// disconnected,
+    pub violation_burst: u32,
+    /// forgiven at this many per second
+    pub violation_rate: f32,
+    /// How often to ping clients
+    pub heartbeat_interval: Duration,
+    /// Clients we haven't heard from in this long are disconnected
+    pub idle_timeout: Duration,
+    /// Clients that fall this many bytes behind on reading what we send them are disconnected
+    pub max_queued_bytes: usize,
+    /// How long the network thread waits between polling sockets. Incoming messages can wait
+    /// this long to be read, outgoing ones are sent as soon as the simulation hands them over.
+    pub network_poll_interval: Duration,
+    /// How often the simulation is sent each connection's statistics
+    pub connection_stats_interval: Duration,
+    /// How often clients are sent the entities near their view
+    pub replication_interval: Duration,
+    /// Entities this close to the edge of a client's view are sent to it, and ones twice as far
+    /// out are removed
+    pub interest_margin: f32,
+    /// Size of the cells entities are bucketed into when deciding what each client can see
+    pub interest_cell_size: f32,
+    /// Views wider or taller than this are cut down, so a client can't ask for the whole map
+    pub max_view_extent: f32,
+    /// Most each client is sent in entity updates. Updates that don't fit wait for the next tick,
+    /// the nearest entities going first.
+    pub replication_bytes_per_sec: usize,
+    /// If set, print how many bytes each client is being sent this often
+    pub replication_report_interval: Option<Duration>,
+    /// Corners of the area entities can path through
+    pub map_min: Point2<f32>,
+    pub map_max: Point2<f32>,
+    /// Size of the squares the map is divided into for pathfinding
+    pub nav_cell_size: f32,
+    /// How far paths keep from obstacles, roughly the size of the things moving
+    pub nav_clearance: f32,
+    /// Distance between the spots in a formation when several entities are sent to one place
+    pub formation_spacing: f32,
+    /// Moving entities closer than this to each other are pushed apart
+    pub separation_radius: f32,
+    /// How far ahead moving entities look for something they're about to run into
+    pub avoidance_horizon: Duration,
+    /// Entities this close to their destination stop if someone is already standing there
+    pub arrival_radius: f32,
     // data directories, etc
 }
 
@@ -25,6 +115,42 @@ impl ServerConfig {
             timestep: Duration::milliseconds(2),
             sim_rate: Duration::milliseconds(33),
             server_address: "127.0.0.1:8844".parse().unwrap(),
+            name: "box server".to_owned(),
+            max_clients: 32,
+            motd: "drink your ovaltine".to_owned(),
+            motd_file: None,
+            scenario: None,
+            default_players: vec!["player".to_owned(), "player 2".to_owned()],
+            discovery_port: Some(DISCOVERY_PORT),
+            auth: AuthConfig::AllowAll,
+            encryption: ServerEncryption::Plaintext,
+            max_chat_length: 256,
+            chat_burst: 5,
+            chat_rate: 0.5,
+            max_message_size: 8192,
+            max_bytes_per_sec: 65536,
+            max_messages_per_sec: 100,
+            violation_burst: 5,
+            violation_rate: 0.1,
+            heartbeat_interval: Duration::seconds(2),
+            idle_timeout: Duration::seconds(15),
+            max_queued_bytes: 256*1024,
+            network_poll_interval: Duration::milliseconds(10),
+            connection_stats_interval: Duration::seconds(1),
+            replication_interval: Duration::milliseconds(50),
+            interest_margin: 5.0,
+            interest_cell_size: 16.0,
+            max_view_extent: 200.0,
+            replication_bytes_per_sec: 65536,
+            replication_report_interval: None,
+            map_min: Point2::new(-50.0, -50.0),
+            map_max: Point2::new(50.0, 50.0),
+            nav_cell_size: 0.5,
+            nav_clearance: 1.0,
+            formation_spacing: 2.5,
+            separation_radius: 2.5,
+            avoidance_horizon: Duration::seconds(1),
+            arrival_radius: 2.0,
         }
     }
 }
@@ -44,31 +170,73 @@ impl ServerSystemContext {
     }
 }
 
+/// The world a server starts with when not given a scenario. The boxes are dealt out between
+/// `players` in turn.
+fn create_default_world(world: &mut specs::World, players: &[String]) {
+    for i in 0..51 {
+        let position = if i == 0 {
+            Point3::new(0.0, 0.0, 0.0)
+        }
+        else {
+            let x = ((((17*i+73)%80)-40) as f32)/2.0;
+            let y = ((((3*i+45)%50)-25) as f32)/2.0;
+            Point3::new(x, y, 0.0)
+        };
+        let e = world.create_now().with(Movement::new_pos(position)).with(Controllable::new()).with(Collider::new(1.0, 1.0)).build();
+        if !players.is_empty() {
+            world.write::<Owner>().insert(e, Owner::new(players[i as usize % players.len()].clone()));
+        }
+    }
+
+    // start at +5, move to -5, slower than everything else
+    let mvmnt = Movement::new_pos_target(Point3::new(5.0, 0.0, 0.0), Point3::new(-5.0, 0.0, 0.0));
+    world.create_now().with(mvmnt).with(MovementStats::new(0.001, 0.000002, 0.000004, 0.00314)).with(Collider::new(1.0, 1.0)).build();
+
+    // a wall along the top, with a gap in the middle
+    for i in 0..20 {
+        let x = (i*2 - 19) as f32;
+        if x.abs() < 2.0 {
+            continue;
+        }
+        world.create_now().with(Movement::new_pos(Point3::new(x, 16.0, 0.0))).with(Obstacle::new(1.0, 1.0)).with(Collider::new(1.0, 1.0)).build();
+    }
+}
+
 pub fn make_server_world(cfg: ServerConfig) -> specs::Planner<Message, ServerSystemContext> {
     let mut world = specs::World::new();
 
     world.register::<Movement>();
     world.register::<Controllable>();
+    world.register::<Owner>();
+    world.register::<Obstacle>();
+    world.register::<Collider>();
+    world.register::<MovementStats>();
+    world.register::<Steering>();
 
-    world.create_now().with(Movement::new()).with(Controllable::new()).build();
-
-    for i in 0..50 {
-        let x = ((((17*i+73)%80)-40) as f32)/2.0;
-        let y = ((((3*i+45)%50)-25) as f32)/2.0;
-        let pos = Movement::new_pos(Point3::new(x, y, 0.0));
-        world.create_now().with(pos).with(Controllable::new()).build();
+    match cfg.scenario {
+        Some(ref path) => load_world(&mut world, path).expect("Failed to load scenario"),
+        None => create_default_world(&mut world, &cfg.default_players),
     }
 
-    // start at +5, move to -5
-    use nalgebra::Point3;
-    let mvmnt = Movement::new_pos_target(Point3::new(5.0, 0.0, 0.0), Point3::new(-5.0, 0.0, 0.0));
-    world.create_now().with(mvmnt).build();
+    let grid = navigation::NavGrid::from_world(&world, cfg.map_min, cfg.map_max, cfg.nav_cell_size, cfg.nav_clearance);
+    world.add_resource(grid);
 
     world.add_resource(IsRunning(true));
+    world.add_resource(Players(HashMap::new()));
+    world.add_resource(ConnectionStats(HashMap::new()));
+    world.add_resource(ClientViews(HashMap::new()));
+    world.add_resource(SnapshotAcks(HashMap::new()));
+    world.add_resource(Bandwidth(HashMap::new()));
+
+    let network = NetworkSystem::new(cfg.clone());
+    let replication = ReplicationSystem::new(&cfg, network.commands());
 
     let mut p = specs::Planner::new(world, 4);
-    p.add_system(MovementSystem::new(), "movement", 2);
-    p.add_system(NetworkSystem::new(cfg), "network", 20);
+    p.add_system(SteeringSystem::new(&cfg), "steering", 3);
+    p.add_system(MovementSystem::new(&cfg), "movement", 2);
+    p.add_system(CollisionSystem::new(), "collision", 1);
+    p.add_system(network, "network", 20);
+    p.add_system(replication, "replication", 15);
 
     p
 }
@@ -101,6 +269,23 @@ impl ServerGame {
     pub fn is_running(&self) -> bool {
         self.running
     }
+
+    /// Ask the game to stop. The main loop should exit and call `shutdown`.
+    pub fn stop(&mut self) {
+        self.planner.mut_world().write_resource::<IsRunning>().0 = false;
+        self.running = false;
+    }
+
+    /// Disconnect all clients, close the listener and optionally save the world to `save_path`.
+    pub fn shutdown(&mut self, save_path: Option<&Path>) -> io::Result<()> {
+        self.planner.message_out.send(Message::Quit);
+        self.planner.handle_messages();
+
+        match save_path {
+            Some(path) => save_world(self.planner.mut_world(), path),
+            None => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,62 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The message of the day sent to clients when they connect.
+///
+/// If a file is given, its contents are used instead of the default text, and the file is
+/// re-read whenever its modification time changes so the motd can be edited while the server is
+/// running.
+pub struct Motd {
+    text: String,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl Motd {
+    pub fn new(default: String, path: Option<PathBuf>) -> Motd {
+        let mut motd = Motd {
+            text: default,
+            path: path,
+            modified: None,
+        };
+        motd.reload();
+        motd
+    }
+
+    /// Returns the current motd, reloading it from disk first if the file has changed.
+    pub fn current(&mut self) -> &str {
+        self.reload();
+        &self.text
+    }
+
+    fn reload(&mut self) {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return,
+        };
+
+        let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(m) => m,
+            Err(error) => {
+                // keep serving the old motd if the file goes missing
+                println!("could not read motd file {:?}: {:?}", path, error);
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        let mut text = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => {
+                println!("loaded motd from {:?}", path);
+                self.text = text.trim_end().to_owned();
+                self.modified = Some(modified);
+            }
+            Err(error) => println!("could not read motd file {:?}: {:?}", path, error),
+        }
+    }
+}
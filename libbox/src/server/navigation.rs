@@ -0,0 +1,302 @@
+//! Pathfinding around static obstacles, on a grid over the map.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use nalgebra::{Point2, Point3, Vector2};
+
+use specs::{Join, World};
+
+use common::components::{Movement, Obstacle};
+
+/// Which parts of the map can be walked on, in square cells.
+#[derive(Clone, Debug)]
+pub struct NavGrid {
+    min: Point2<f32>,
+    cell_size: f32,
+    width: usize,
+    height: usize,
+    blocked: Vec<bool>,
+}
+
+type Cell = (usize, usize);
+
+/// An entry in the A* open set. Ordered so the `BinaryHeap` pops the lowest estimate first.
+#[derive(PartialEq)]
+struct Open {
+    estimate: f32,
+    cell: Cell,
+}
+
+impl Eq for Open { }
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Distance on a grid where diagonal steps are allowed.
+fn octile(a: Cell, b: Cell) -> f32 {
+    let dx = (a.0 as f32 - b.0 as f32).abs();
+    let dy = (a.1 as f32 - b.1 as f32).abs();
+    dx.max(dy) + (2.0f32.sqrt() - 1.0)*dx.min(dy)
+}
+
+impl NavGrid {
+    /// An empty grid covering the rectangle from `min` to `max`.
+    pub fn new(min: Point2<f32>, max: Point2<f32>, cell_size: f32) -> NavGrid {
+        let width = ((max.x - min.x)/cell_size).ceil().max(1.0) as usize;
+        let height = ((max.y - min.y)/cell_size).ceil().max(1.0) as usize;
+        NavGrid {
+            min: min,
+            cell_size: cell_size,
+            width: width,
+            height: height,
+            blocked: vec![false; width*height],
+        }
+    }
+
+    /// A grid with every `Obstacle` in the world blocked out, grown by `clearance` so that
+    /// entities following a path don't clip the corners.
+    pub fn from_world(world: &World, min: Point2<f32>, max: Point2<f32>, cell_size: f32, clearance: f32) -> NavGrid {
+        let mut grid = NavGrid::new(min, max, cell_size);
+        let movement = world.read::<Movement>();
+        let obstacles = world.read::<Obstacle>();
+        for (m, o) in (&movement, &obstacles).iter() {
+            let center = Point2::new(m.position.x, m.position.y);
+            grid.block(center, o.half_extents + Vector2::new(clearance, clearance));
+        }
+        grid
+    }
+
+    /// Mark every cell overlapping the rectangle as blocked.
+    pub fn block(&mut self, center: Point2<f32>, half_extents: Vector2<f32>) {
+        let (min_x, min_y) = self.clamped_cell(center.x - half_extents.x, center.y - half_extents.y);
+        let (max_x, max_y) = self.clamped_cell(center.x + half_extents.x, center.y + half_extents.y);
+        for y in min_y..max_y+1 {
+            for x in min_x..max_x+1 {
+                let i = self.index((x, y));
+                self.blocked[i] = true;
+            }
+        }
+    }
+
+    fn index(&self, cell: Cell) -> usize {
+        cell.1*self.width + cell.0
+    }
+
+    fn clamped_cell(&self, x: f32, y: f32) -> Cell {
+        let cx = ((x - self.min.x)/self.cell_size).floor().max(0.0) as usize;
+        let cy = ((y - self.min.y)/self.cell_size).floor().max(0.0) as usize;
+        (cx.min(self.width - 1), cy.min(self.height - 1))
+    }
+
+    /// Whether `position` is inside the grid. Never true for NaN.
+    fn on_map(&self, position: &Point3<f32>) -> bool {
+        let max_x = self.min.x + self.width as f32*self.cell_size;
+        let max_y = self.min.y + self.height as f32*self.cell_size;
+        self.min.x <= position.x && position.x < max_x && self.min.y <= position.y && position.y < max_y
+    }
+
+    fn center(&self, cell: Cell) -> Point3<f32> {
+        Point3::new(self.min.x + (cell.0 as f32 + 0.5)*self.cell_size, self.min.y + (cell.1 as f32 + 0.5)*self.cell_size, 0.0)
+    }
+
+    pub fn is_blocked(&self, position: &Point3<f32>) -> bool {
+        let cell = self.clamped_cell(position.x, position.y);
+        self.blocked[self.index(cell)]
+    }
+
+    fn neighbours(&self, cell: Cell) -> Vec<(Cell, f32)> {
+        let mut found = Vec::with_capacity(8);
+        for &(dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (-1, 1), (1, -1), (1, 1)] {
+            let x = cell.0 as isize + dx;
+            let y = cell.1 as isize + dy;
+            if x < 0 || y < 0 || x >= self.width as isize || y >= self.height as isize {
+                continue;
+            }
+            let next = (x as usize, y as usize);
+            if self.blocked[self.index(next)] {
+                continue;
+            }
+            if dx != 0 && dy != 0 {
+                // don't cut corners
+                let side_a = (next.0, cell.1);
+                let side_b = (cell.0, next.1);
+                if self.blocked[self.index(side_a)] || self.blocked[self.index(side_b)] {
+                    continue;
+                }
+                found.push((next, 2.0f32.sqrt()));
+            }
+            else {
+                found.push((next, 1.0));
+            }
+        }
+        found
+    }
+
+    /// The nearest cell to `cell` that isn't blocked, if there is one.
+    fn nearest_open(&self, cell: Cell) -> Option<Cell> {
+        if !self.blocked[self.index(cell)] {
+            return Some(cell);
+        }
+        let mut seen = vec![false; self.blocked.len()];
+        let mut queue = VecDeque::new();
+        seen[self.index(cell)] = true;
+        queue.push_back(cell);
+        while let Some(current) = queue.pop_front() {
+            if !self.blocked[self.index(current)] {
+                return Some(current);
+            }
+            for &(dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                let x = current.0 as isize + dx;
+                let y = current.1 as isize + dy;
+                if x < 0 || y < 0 || x >= self.width as isize || y >= self.height as isize {
+                    continue;
+                }
+                let next = (x as usize, y as usize);
+                let i = self.index(next);
+                if !seen[i] {
+                    seen[i] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    /// `position` if it's open, otherwise the middle of the nearest open cell. None if the whole
+    /// map is blocked.
+    pub fn nearest_open_point(&self, position: &Point3<f32>) -> Option<Point3<f32>> {
+        let cell = self.clamped_cell(position.x, position.y);
+        self.nearest_open(cell).map(|open| if open == cell && self.on_map(position) { *position } else { self.center(open) })
+    }
+
+    /// Whether a straight line between two points stays clear of blocked cells. Walks every
+    /// cell the line passes through.
+    fn line_of_sight(&self, from: &Point3<f32>, to: &Point3<f32>) -> bool {
+        use std::f32;
+
+        let (mut x, mut y) = { let c = self.clamped_cell(from.x, from.y); (c.0 as isize, c.1 as isize) };
+        let end = self.clamped_cell(to.x, to.y);
+        let (end_x, end_y) = (end.0 as isize, end.1 as isize);
+
+        let dx = to.x - from.x;
+        let dy = to.y - from.y;
+        let step_x = if dx > 0.0 { 1 } else { -1 };
+        let step_y = if dy > 0.0 { 1 } else { -1 };
+
+        // how far along the line, as a fraction, the next cell boundary in each direction is
+        let boundary = |cell: isize, min: f32, start: f32, delta: f32| {
+            if delta == 0.0 {
+                return f32::INFINITY;
+            }
+            let edge = min + (cell as f32 + if delta > 0.0 { 1.0 } else { 0.0 })*self.cell_size;
+            (edge - start)/delta
+        };
+        let mut next_x = boundary(x, self.min.x, from.x, dx);
+        let mut next_y = boundary(y, self.min.y, from.y, dy);
+        let step_t_x = if dx == 0.0 { f32::INFINITY } else { self.cell_size/dx.abs() };
+        let step_t_y = if dy == 0.0 { f32::INFINITY } else { self.cell_size/dy.abs() };
+
+        loop {
+            if x < 0 || y < 0 || x >= self.width as isize || y >= self.height as isize {
+                return true; // off the map, nothing left to hit
+            }
+            if self.blocked[self.index((x as usize, y as usize))] {
+                return false;
+            }
+            if (x, y) == (end_x, end_y) || (next_x > 1.0 && next_y > 1.0) {
+                return true;
+            }
+            if next_x < next_y {
+                next_x += step_t_x;
+                x += step_x;
+            }
+            else {
+                next_y += step_t_y;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Waypoints from `start` to `goal` that avoid blocked cells, not including `start`. If the
+    /// goal is blocked or off the map, the path ends at the nearest open cell instead. Returns
+    /// None if there is no way there at all.
+    pub fn find_path(&self, start: Point3<f32>, goal: Point3<f32>) -> Option<Vec<Point3<f32>>> {
+        let start_cell = self.clamped_cell(start.x, start.y);
+        let requested = self.clamped_cell(goal.x, goal.y);
+        let goal_cell = match self.nearest_open(requested) {
+            Some(cell) => cell,
+            None => return None,
+        };
+        let goal = if goal_cell == requested && self.on_map(&goal) { goal } else { self.center(goal_cell) };
+
+        if self.line_of_sight(&start, &goal) {
+            return Some(vec![goal]);
+        }
+
+        // plain A*. the start cell may be blocked if we're standing too close to something, that's
+        // fine as long as we step out of it
+        let mut came_from: HashMap<Cell, Cell> = HashMap::new();
+        let mut cost: HashMap<Cell, f32> = HashMap::new();
+        let mut open = BinaryHeap::new();
+        cost.insert(start_cell, 0.0);
+        open.push(Open { estimate: octile(start_cell, goal_cell), cell: start_cell });
+
+        let mut found = false;
+        while let Some(Open { cell, .. }) = open.pop() {
+            if cell == goal_cell {
+                found = true;
+                break;
+            }
+            let current_cost = cost[&cell];
+            for (next, step) in self.neighbours(cell) {
+                let next_cost = current_cost + step;
+                if cost.get(&next).map_or(true, |&c| next_cost < c) {
+                    cost.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Open { estimate: next_cost + octile(next, goal_cell), cell: next });
+                }
+            }
+        }
+        if !found {
+            return None;
+        }
+
+        let mut cells = vec![goal_cell];
+        while let Some(&previous) = came_from.get(cells.last().unwrap()) {
+            cells.push(previous);
+        }
+        cells.reverse();
+
+        let mut points: Vec<Point3<f32>> = cells.into_iter().skip(1).map(|c| self.center(c)).collect();
+        points.pop();
+        points.push(goal);
+
+        // skip every waypoint we can see past
+        let mut path = Vec::new();
+        let mut from = start;
+        let mut i = 0;
+        while i < points.len() {
+            let mut furthest = i;
+            for j in (i+1..points.len()).rev() {
+                if self.line_of_sight(&from, &points[j]) {
+                    furthest = j;
+                    break;
+                }
+            }
+            from = points[furthest];
+            path.push(from);
+            i = furthest + 1;
+        }
+        Some(path)
+    }
+}
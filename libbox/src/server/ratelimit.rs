@@ -0,0 +1,76 @@
+use time::Duration;
+
+use server::ServerConfig;
+
+/// A token bucket allowing bursts of up to `capacity` units, refilled at `rate` units per second.
+///
+/// The bucket is refilled explicitly with the elapsed simulation time rather than reading the
+/// clock, so it behaves the same in tests as in the game loop.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    capacity: f32,
+    rate: f32,
+    tokens: f32,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f32, rate: f32) -> RateLimiter {
+        RateLimiter {
+            capacity: capacity,
+            rate: rate,
+            tokens: capacity,
+        }
+    }
+
+    pub fn refill(&mut self, elapsed: Duration) {
+        let secs = elapsed.num_milliseconds() as f32 / 1000.0;
+        self.tokens = (self.tokens + secs*self.rate).min(self.capacity);
+    }
+
+    /// Take `amount` units if there are enough left, returning whether they were taken.
+    pub fn try_take(&mut self, amount: f32) -> bool {
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    /// Take `amount` units even if there aren't enough, emptying the bucket, and return whether
+    /// there were enough. For things that have already happened and can't be refused.
+    pub fn charge(&mut self, amount: f32) -> bool {
+        let within = self.tokens >= amount;
+        self.tokens = (self.tokens - amount).max(0.0);
+        within
+    }
+}
+
+/// The limits applied to each client connection.
+#[derive(Clone, Debug)]
+pub struct ClientLimits {
+    pub bytes: RateLimiter,
+    pub messages: RateLimiter,
+    pub chat: RateLimiter,
+    /// Each broken limit takes a token, and the client is disconnected when they run out
+    pub violations: RateLimiter,
+}
+
+impl ClientLimits {
+    pub fn new(cfg: &ServerConfig) -> ClientLimits {
+        ClientLimits {
+            bytes: RateLimiter::new(cfg.max_bytes_per_sec as f32, cfg.max_bytes_per_sec as f32),
+            messages: RateLimiter::new(cfg.max_messages_per_sec as f32, cfg.max_messages_per_sec as f32),
+            chat: RateLimiter::new(cfg.chat_burst as f32, cfg.chat_rate),
+            violations: RateLimiter::new(cfg.violation_burst as f32, cfg.violation_rate),
+        }
+    }
+
+    pub fn refill(&mut self, elapsed: Duration) {
+        self.bytes.refill(elapsed);
+        self.messages.refill(elapsed);
+        self.chat.refill(elapsed);
+        self.violations.refill(elapsed);
+    }
+}
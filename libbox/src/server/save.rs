@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use rustc_serialize::json;
+
+use specs::{Join, World};
+
+use common::components::{Collider, Controllable, Movement, MovementStats, Obstacle, Owner};
+
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+struct SavedEntity {
+    movement: Movement,
+    controllable: bool,
+    /// Who can give it orders. Nobody if there's no owner
+    owner: Option<Owner>,
+    stats: Option<MovementStats>,
+    collider: Option<Collider>,
+    obstacle: Option<Obstacle>,
+}
+
+/// Write every entity with a `Movement` component to `path` as json.
+pub fn save_world(world: &World, path: &Path) -> io::Result<()> {
+    let entities = world.entities();
+    let movement = world.read::<Movement>();
+    let control = world.read::<Controllable>();
+    let owners = world.read::<Owner>();
+    let stats = world.read::<MovementStats>();
+    let colliders = world.read::<Collider>();
+    let obstacles = world.read::<Obstacle>();
+
+    let saved: Vec<SavedEntity> = (&entities, &movement).iter()
+        .map(|(e, m)| SavedEntity {
+            movement: m.clone(),
+            controllable: control.get(e).is_some(),
+            owner: owners.get(e).cloned(),
+            stats: stats.get(e).cloned(),
+            collider: colliders.get(e).cloned(),
+            obstacle: obstacles.get(e).cloned(),
+        })
+        .collect();
+
+    let encoded = json::encode(&saved)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut file = File::create(path)?;
+    file.write_all(encoded.as_bytes())
+}
+
+/// Create the entities in a file written by `save_world`. Scenarios are written in the same
+/// format.
+pub fn load_world(world: &mut World, path: &Path) -> io::Result<()> {
+    let mut encoded = String::new();
+    File::open(path)?.read_to_string(&mut encoded)?;
+    let saved: Vec<SavedEntity> = json::decode(&encoded)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    for entity in saved {
+        let mut builder = world.create_now().with(entity.movement);
+        if entity.controllable {
+            builder = builder.with(Controllable::new());
+        }
+        if let Some(owner) = entity.owner {
+            builder = builder.with(owner);
+        }
+        if let Some(stats) = entity.stats {
+            builder = builder.with(stats);
+        }
+        if let Some(collider) = entity.collider {
+            builder = builder.with(collider);
+        }
+        if let Some(obstacle) = entity.obstacle {
+            builder = builder.with(obstacle);
+        }
+        builder.build();
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+
+use specs::{Entity, Join, MessageQueue, RunArg, System, World};
+
+use ncollide::shape::Cuboid;
+use ncollide::bounding_volume::{self, AABB};
+use ncollide::broad_phase::{BroadPhase, DBVTBroadPhase};
+
+use nalgebra;
+use nalgebra::{Isometry3, Point3, Vector2, Vector3};
+
+use server::ServerSystemContext;
+
+use common::Message;
+use common::components::{Collider, Movement, Obstacle};
+
+/// How far two boxes overlap, and which way the second has to move to get out of the first.
+/// None if they don't overlap.
+pub fn penetration(a: &Vector2<f32>, a_extents: &Vector2<f32>, b: &Vector2<f32>, b_extents: &Vector2<f32>) -> Option<Vector2<f32>> {
+    let delta = *b - *a;
+    let overlap_x = a_extents.x + b_extents.x - delta.x.abs();
+    let overlap_y = a_extents.y + b_extents.y - delta.y.abs();
+    if overlap_x <= 0.0 || overlap_y <= 0.0 {
+        return None;
+    }
+
+    // push out along whichever axis needs the smaller push
+    if overlap_x < overlap_y {
+        let sign = if delta.x < 0.0 { -1.0 } else { 1.0 };
+        Some(Vector2::new(sign*overlap_x, 0.0))
+    }
+    else {
+        let sign = if delta.y < 0.0 { -1.0 } else { 1.0 };
+        Some(Vector2::new(0.0, sign*overlap_y))
+    }
+}
+
+/// Extra room around each box in the broad phase, so that small moves don't have to reshuffle it
+const BROAD_PHASE_MARGIN: f32 = 0.25;
+
+fn bounding_box(m: &Movement, c: &Collider) -> AABB<Point3<f32>> {
+    let shape = Cuboid::new(Vector3::new(c.half_extents.x, c.half_extents.y, 0.0));
+    let pos = Isometry3::new(m.position.to_vector(), nalgebra::zero());
+    bounding_volume::aabb(&shape, &pos)
+}
+
+/// A collider's entry in the broad phase.
+struct Proxy {
+    uid: usize,
+    /// Where the box was when the broad phase was last told about it
+    position: Point3<f32>,
+    half_extents: Vector2<f32>,
+}
+
+/// Finds boxes that overlap and pushes them apart. Obstacles don't move, so anything touching one
+/// is pushed all the way out.
+pub struct CollisionSystem {
+    broad_phase: DBVTBroadPhase<Point3<f32>, AABB<Point3<f32>>, Entity>,
+    proxies: HashMap<Entity, Proxy>,
+    next_uid: usize,
+    /// Pairs whose bounding boxes overlap, kept up to date from what the broad phase reports
+    pairs: HashSet<(Entity, Entity)>,
+}
+
+impl CollisionSystem {
+    pub fn new() -> CollisionSystem {
+        CollisionSystem {
+            broad_phase: DBVTBroadPhase::new(BROAD_PHASE_MARGIN, false),
+            proxies: HashMap::new(),
+            next_uid: 0,
+            pairs: HashSet::new(),
+        }
+    }
+
+    /// Tell the broad phase about colliders that appeared, moved or went away, and find out
+    /// which pairs started or stopped overlapping.
+    fn update_broad_phase(&mut self, colliding: &[(Entity, &Movement, &Collider)]) {
+        let mut present = HashSet::new();
+        for &(e, m, c) in colliding {
+            present.insert(e);
+            if let Some(proxy) = self.proxies.get_mut(&e) {
+                if proxy.position != m.position || proxy.half_extents != c.half_extents {
+                    self.broad_phase.deferred_set_bounding_volume(proxy.uid, bounding_box(m, c));
+                    proxy.position = m.position;
+                    proxy.half_extents = c.half_extents;
+                }
+                continue;
+            }
+            // uids are never reused, so a new entity in a deleted one's slot can't be confused
+            // with it before the removal is processed
+            self.broad_phase.deferred_add(self.next_uid, bounding_box(m, c), e);
+            self.proxies.insert(e, Proxy { uid: self.next_uid, position: m.position, half_extents: c.half_extents });
+            self.next_uid += 1;
+        }
+
+        let broad_phase = &mut self.broad_phase;
+        self.proxies.retain(|e, proxy| {
+            if !present.contains(e) {
+                broad_phase.deferred_remove(proxy.uid);
+            }
+            present.contains(e)
+        });
+        self.pairs.retain(|&(a, b)| present.contains(&a) && present.contains(&b));
+
+        let pairs = &mut self.pairs;
+        broad_phase.update(&mut |a, b| *a != *b, &mut |a, b, started| {
+            if started {
+                pairs.insert((*a, *b));
+            }
+            else {
+                pairs.remove(&(*a, *b));
+                pairs.remove(&(*b, *a));
+            }
+        });
+    }
+}
+
+impl System<Message, ServerSystemContext> for CollisionSystem {
+    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, _: ServerSystemContext) {
+        let (entities, mut movement, colliders, obstacles) = arg.fetch(|w| {
+            (w.entities(), w.write::<Movement>(), w.read::<Collider>(), w.read::<Obstacle>())
+        });
+
+        {
+            let colliding: Vec<_> = (&entities, &movement, &colliders).iter().collect();
+            self.update_broad_phase(&colliding);
+        }
+
+        // total push for each entity, so one caught between two others ends up in the middle
+        let mut pushes: HashMap<Entity, Vector2<f32>> = HashMap::new();
+        for &(a, b) in &self.pairs {
+            let (pos_a, pos_b) = match (movement.get(a), movement.get(b)) {
+                (Some(ma), Some(mb)) => (Vector2::new(ma.position.x, ma.position.y), Vector2::new(mb.position.x, mb.position.y)),
+                _ => continue,
+            };
+            let push = match penetration(&pos_a, &colliders.get(a).unwrap().half_extents, &pos_b, &colliders.get(b).unwrap().half_extents) {
+                Some(push) => push,
+                None => continue, // the bounding boxes only touch
+            };
+
+            let (share_a, share_b) = match (obstacles.get(a).is_some(), obstacles.get(b).is_some()) {
+                (true, true) => continue,
+                (true, false) => (0.0, 1.0),
+                (false, true) => (1.0, 0.0),
+                (false, false) => (0.5, 0.5),
+            };
+            let total_a = pushes.entry(a).or_insert_with(nalgebra::zero);
+            *total_a = *total_a - push*share_a;
+            let total_b = pushes.entry(b).or_insert_with(nalgebra::zero);
+            *total_b = *total_b + push*share_b;
+        }
+
+        for (e, push) in pushes {
+            if let Some(m) = movement.get_mut(e) {
+                m.position.x += push.x;
+                m.position.y += push.y;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, _: &mut World, _: &Message) { }
+}
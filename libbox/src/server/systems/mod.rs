@@ -1,5 +1,11 @@
+mod collision;
 mod movement;
 mod network;
+mod replication;
+mod steering;
 
+pub use self::collision::*;
 pub use self::movement::*;
 pub use self::network::*;
+pub use self::replication::*;
+pub use self::steering::*;
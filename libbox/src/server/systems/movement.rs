@@ -1,50 +1,95 @@
-use time::Duration;
+use std::collections::HashSet;
 
-use specs::{Join, MessageQueue, RunArg, System, World};
+use specs::{Entity, Join, MessageQueue, RunArg, System, World};
 
-use server::ServerSystemContext;
+use nalgebra::Point3;
 
-use common::Message;
-use common::components::{Controllable, Movement};
-use common::resources::CurrentHover;
+use server::{ServerConfig, ServerSystemContext};
+use server::navigation::NavGrid;
+use server::systems::{assign_slots, formation_slots};
 
-use nalgebra::Point3;
+use common::Message;
+use common::components::{Controllable, Movement, MovementStats, Owner, Steering};
+use common::resources::{CurrentHover, Players};
 
 
-pub struct MovementSystem { }
+pub struct MovementSystem {
+    spacing: f32,
+}
 
 impl MovementSystem {
-    pub fn new() -> MovementSystem {
-        MovementSystem { }
+    pub fn new(cfg: &ServerConfig) -> MovementSystem {
+        MovementSystem {
+            spacing: cfg.formation_spacing,
+        }
+    }
+
+    /// Send the members of `group` that `client_id` may give orders to to spots around
+    /// `target`, each along its own path. With `queue`, they go there after the rest of their
+    /// paths.
+    fn command_group(&self, world: &mut World, client_id: u16, group: &[Entity], target: Point3<f32>, queue: bool) {
+        let mut movement = world.write::<Movement>();
+        let grid = world.read_resource::<NavGrid>();
+
+        let mut seen = HashSet::new();
+        let members: Vec<Entity> = group.iter().cloned()
+            .filter(|&e| obeys(world, e, client_id) && movement.get(e).is_some() && seen.insert(e))
+            .collect();
+
+        let starts: Vec<Point3<f32>> = members.iter().map(|&e| {
+            let m = movement.get(e).unwrap();
+            if queue { m.path.waypoints.back().cloned().unwrap_or(m.position) } else { m.position }
+        }).collect();
+        // a target inside something gathers everyone at the nearest spot they can stand on
+        let target = match grid.nearest_open_point(&target) {
+            Some(target) => target,
+            None => return,
+        };
+        let slots = formation_slots(target, members.len(), self.spacing, &grid);
+
+        for ((&e, &from), slot) in members.iter().zip(&starts).zip(assign_slots(&starts, &slots)) {
+            // if the area is too crowded to fit everyone, the rest just head for the target
+            let goal = slot.map_or(target, |s| slots[s]);
+            let m = movement.get_mut(e).unwrap();
+            match grid.find_path(from, goal) {
+                Some(path) if queue => m.path.waypoints.extend(path),
+                Some(path) => m.set_path(path),
+                None => println!("no path from {:?} to {:?}", from, goal),
+            }
+        }
     }
+}
 
-	fn do_movement(&mut self, m: &mut Movement, timestep: Duration) {
-		let (begin, end, mut t) = m.current_path.unwrap();
-
-		let progress = m.speed*(timestep.num_milliseconds() as f32);
-		t += progress;
-
-		if t >= 1.0 {
-			m.position = end;
-			m.current_path = None;
-		}
-		else {
-			let new_position = lerpf32(&begin, &end, &t);
-			m.position = new_position;
-			m.current_path = Some((begin, end, t));
-		}
-	}
+/// Targets come from clients, and there's no getting to one that isn't a number.
+fn finite(target: &Point3<f32>) -> bool {
+    target.x.is_finite() && target.y.is_finite() && target.z.is_finite()
+}
+
+/// Whether `e` takes orders from the player connected as `client_id`.
+fn obeys(world: &World, e: Entity, client_id: u16) -> bool {
+    let players = world.read_resource::<Players>();
+    let player = match players.0.get(&client_id) {
+        Some(player) => player,
+        None => return false,
+    };
+    world.read::<Controllable>().get(e).is_some() && world.read::<Owner>().get(e).map_or(false, |o| o.player == *player)
 }
 
 impl System<Message, ServerSystemContext> for MovementSystem {
     fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, ctx: ServerSystemContext) {
-        let mut mvt = arg.fetch(|w| w.write::<Movement>());
+        let (entities, mut mvt, stats, mut steering) = arg.fetch(|w| {
+            (w.entities(), w.write::<Movement>(), w.read::<MovementStats>(), w.write::<Steering>())
+        });
 
+        let default_stats = MovementStats::default();
+        let ms = ctx.timestep.num_milliseconds() as f32;
         let mut accum = ctx.dt;
         while accum > ctx.timestep {
-            for m in (&mut mvt).iter() {
-                if m.current_path.is_some() {
-                    self.do_movement(m, ctx.timestep);
+            for (e, m) in (&entities, &mut mvt).iter() {
+                let stats = stats.get(e).unwrap_or(&default_stats);
+                match steering.get_mut(e) {
+                    Some(steer) => m.advance_steered(stats, steer, ms),
+                    None => m.advance(stats, ms),
                 }
             }
             accum = accum - ctx.timestep;
@@ -53,29 +98,46 @@ impl System<Message, ServerSystemContext> for MovementSystem {
 
     fn handle_message(&mut self, world: &mut World, msg: &Message) {
         match *msg {
-            Message::InteractWith(e, ref interact) => {
-                // TODO check that client is allowed to perform this action
-                // may need to have separate client messages and server messages
-                // these would still be in common though since server would have to translate
-                // between them
-                let control = world.read::<Controllable>();
+            Message::InteractWith(client_id, e, ref interact) => {
+                if !obeys(world, e, client_id) {
+                    return;
+                }
                 let mut movement = world.write::<Movement>();
-                control.get(e)
-                .and_then(|_| movement.get_mut(e))
+                let grid = world.read_resource::<NavGrid>();
+                movement.get_mut(e)
                 .map(|m| match *interact {
-                    CurrentHover::Ground(target) => m.set_target(target),
+                    CurrentHover::Ground(target) if finite(&target) => {
+                        match grid.find_path(m.position, target) {
+                            Some(path) => m.set_path(path),
+                            None => println!("no path from {:?} to {:?}", m.position, target),
+                        }
+                    },
                     _ => (),
                 });
             },
+            Message::QueueInteraction(client_id, e, ref interact) => {
+                if !obeys(world, e, client_id) {
+                    return;
+                }
+                let mut movement = world.write::<Movement>();
+                let grid = world.read_resource::<NavGrid>();
+                movement.get_mut(e)
+                .map(|m| match *interact {
+                    CurrentHover::Ground(target) if finite(&target) => {
+                        // carry on from wherever the current path ends
+                        let from = m.path.waypoints.back().cloned().unwrap_or(m.position);
+                        match grid.find_path(from, target) {
+                            Some(path) => m.path.waypoints.extend(path),
+                            None => println!("no path from {:?} to {:?}", from, target),
+                        }
+                    },
+                    _ => (),
+                });
+            },
+            Message::CommandGroup(client_id, ref group, CurrentHover::Ground(target), queue) if finite(&target) => {
+                self.command_group(world, client_id, group, target, queue);
+            },
             _ => (),
         }
     }
 }
-
-pub fn lerpf32(begin: &Point3<f32>, end: &Point3<f32>, t: &f32) -> Point3<f32> {
-    let x = (1.0 - t)*begin.x + t*end.x;
-    let y = (1.0 - t)*begin.y + t*end.y;
-    let z = (1.0 - t)*begin.z + t*end.z;
-
-    Point3::new(x, y, z)
-}
@@ -1,142 +1,99 @@
-use std::io::{self, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-use std::net::{TcpListener, TcpStream};
-
-use std::num::Wrapping;
-
-use rustc_serialize::json;
+use std::thread::{self, JoinHandle};
 
 use specs::{MessageQueue, RunArg, System, World};
 
 use server::{ServerConfig, ServerSystemContext};
+use server::connections::{NetworkCommand, NetworkEvent, NetworkServer};
 
-use common::{Message, NetworkMessage};
-
-struct ClientConnection {
-    pub stream: TcpStream,
-    pub client_id: u16,
-}
-
-impl ClientConnection {
-    pub fn new(stream: TcpStream, client_id: u16) -> ClientConnection {
-        stream.set_nodelay(true).unwrap();
-        stream.set_nonblocking(true).unwrap();
-        ClientConnection {
-            stream: stream,
-            client_id: client_id,
-        }
-    }
-}
+use common::Message;
+use common::resources::{ClientViews, ConnectionStats, Players, SnapshotAcks};
 
+/// Relays between the world and the network thread. Never touches a socket itself.
 pub struct NetworkSystem {
-    connected_clients: Vec<ClientConnection>, // hashmap may be better
-    listener: TcpListener,
-    current_id: Wrapping<u16>,
+    commands: Sender<NetworkCommand>,
+    events: Receiver<NetworkEvent>,
+    thread: Option<JoinHandle<()>>,
 }
 
 impl NetworkSystem {
     pub fn new(cfg: ServerConfig) -> NetworkSystem {
-        let listener = TcpListener::bind(cfg.server_address).unwrap();
-        listener.set_nonblocking(true).unwrap();
+        let (event_tx, event_rx) = channel();
+        let (command_tx, command_rx) = channel();
+
+        // bind here so a bad address fails on startup rather than on the thread
+        let server = NetworkServer::new(cfg, event_tx);
+        let thread = thread::Builder::new()
+            .name("network".to_owned())
+            .spawn(move || server.run(command_rx))
+            .expect("Failed to start network thread");
+
         NetworkSystem {
-            connected_clients: Vec::new(),
-            listener: listener,
-            current_id: Wrapping(0u16),
+            commands: command_tx,
+            events: event_rx,
+            thread: Some(thread),
         }
     }
 
-    fn handle_new_connection(&mut self, stream: TcpStream) {
-        // TODO do a search for unused client id and err if we don't have one
-        // but actually we should just reject new connections if we are full
-        self.connected_clients.push(ClientConnection::new(stream, self.current_id.0));
-        self.current_id += Wrapping(1);
+    /// For other systems that need to send to clients.
+    pub fn commands(&self) -> Sender<NetworkCommand> {
+        self.commands.clone()
     }
 
-    fn handle_incoming_connections(&mut self) {
-        loop {
-            let stream = self.listener.accept();
-            match stream {
-                Ok(s) => {
-                    self.handle_new_connection(s.0);
-                }
-                Err(_) => {
-                    break;
-                    // don't know what to do here yet.
-                }
+    /// Stop the network thread and wait for it to say goodbye to everyone.
+    fn shutdown(&mut self) {
+        let _ = self.commands.send(NetworkCommand::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                println!("network thread panicked");
             }
         }
     }
+}
 
-    fn handle_incoming_messages(&mut self) {
-        let mut buf = String::new();
-        for client in &mut self.connected_clients {
-            match client.stream.read_to_string(&mut buf) {
-                Ok(_) => {},
-                Err(error) => {
-                    // apparently with nonblocking sockets, it returns WouldBlock when the read is
-                    // successful?
-                    if error.kind() == io::ErrorKind::WouldBlock ||
-                       error.kind() == io::ErrorKind::TimedOut {}
-                    else {
-                        // actual error
-                        println!("{:?}", error);
-                        continue;
-                    }
+impl System<Message, ServerSystemContext> for NetworkSystem {
+    fn run(&mut self, arg: RunArg, msgq: MessageQueue<Message>, _: ServerSystemContext) {
+        let (mut players, mut stats, mut views, mut acks) = arg.fetch(|w| {
+            (
+                w.write_resource::<Players>(),
+                w.write_resource::<ConnectionStats>(),
+                w.write_resource::<ClientViews>(),
+                w.write_resource::<SnapshotAcks>(),
+            )
+        });
+
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                NetworkEvent::Connected(id, name) => {
+                    players.0.insert(id, name);
                 },
-            }
-
-            if buf.len() < 1 {
-                continue;
-            }
-
-            let msg: NetworkMessage;
-            match json::decode(&buf) {
-                Ok(m) => {msg = m},
-                Err(error) => {
-                    println!("error decoding message: {:?}", error);
-                    println!("{}", buf);
-                    continue;
-                }
-            }
-
-            // TODO lots of validation here, and attach client id to messages somehow
-            use common::NetworkMessage::*;
-            match msg {
-                GameMessage(_) => (),
-                Connect(version) => {
-                    if version.0.as_str() != env!("CARGO_PKG_VERSION") {
-                        println!("client {} sent wrong version string", client.client_id);
-                        // TODO send disconnect
-                    }
-                    else {
-                        println!("sending motd to client {}", client.client_id);
-                        let message = NetworkMessage::Motd("drink your ovaltine".to_owned());
-                        let message = json::encode(&message).unwrap();
-                        client.stream.write(message.as_bytes()).unwrap();
-                    }
+                NetworkEvent::Disconnected(id) => {
+                    players.0.remove(&id);
+                    views.0.remove(&id);
+                    acks.0.remove(&id);
                 },
-                Motd(_) => (),
-                Disconnect(_) => {
-                    // close connection
-                }
+                NetworkEvent::Command(id, command) => msgq.send(command.sent_by(id)),
+                NetworkEvent::View(id, area) => {
+                    views.0.insert(id, area);
+                },
+                NetworkEvent::AckSnapshot(id, sequence) => {
+                    acks.0.insert(id, sequence);
+                },
+                NetworkEvent::Stats(latest) => stats.0 = latest,
             }
-
-            buf.clear();
-
         }
     }
-}
-
-impl System<Message, ServerSystemContext> for NetworkSystem {
-    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, _: ServerSystemContext) {
-        let _ = arg.fetch(|_| {});
-
-        self.handle_incoming_connections();
-        self.handle_incoming_messages();
-    }
 
-    fn handle_message(&mut self, _: &mut World, msg: &Message) {
+    fn handle_message(&mut self, world: &mut World, msg: &Message) {
         match *msg {
+            Message::Quit => {
+                self.shutdown();
+                world.write_resource::<Players>().0.clear();
+                world.write_resource::<ConnectionStats>().0.clear();
+                world.write_resource::<ClientViews>().0.clear();
+                world.write_resource::<SnapshotAcks>().0.clear();
+            },
             _ => (),
         }
     }
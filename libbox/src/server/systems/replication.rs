@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use std::sync::mpsc::Sender;
+
+use time::Duration;
+
+use rustc_serialize::json;
+
+use specs::{Entity, Join, MessageQueue, RunArg, System, World};
+
+use nalgebra;
+use nalgebra::Point2;
+
+use server::{ServerConfig, ServerSystemContext};
+use server::connections::NetworkCommand;
+use server::interest::SpatialGrid;
+
+use common::{Message, NetworkMessage};
+use common::components::{Controllable, Movement, MovementStats, Owner};
+use common::replication::{EntityState, Replication, Snapshot};
+use common::resources::{Bandwidth, BandwidthStats, ClientViews, Players, SnapshotAcks};
+
+/// Snapshots a client may leave unacknowledged before we assume it has them.
+const MAX_IN_FLIGHT: usize = 128;
+
+/// What one client has been told.
+struct ClientReplication {
+    /// Entity state as of the last snapshot the client acknowledged. Changes are sent relative
+    /// to this.
+    acked: HashMap<Entity, EntityState>,
+    /// Snapshots sent but not acknowledged yet, oldest first
+    in_flight: VecDeque<Snapshot>,
+    /// Every entity ever spawned on the client and not destroyed since. Clients keep entities that
+    /// left the view in control groups, so they have to hear about these wherever they are.
+    sent: HashSet<Entity>,
+    next_sequence: u32,
+    stats: BandwidthStats,
+}
+
+impl ClientReplication {
+    fn new() -> ClientReplication {
+        ClientReplication {
+            acked: HashMap::new(),
+            in_flight: VecDeque::new(),
+            sent: HashSet::new(),
+            next_sequence: 0,
+            stats: BandwidthStats::default(),
+        }
+    }
+
+    /// Fold every snapshot up to and including `sequence` into the acknowledged state.
+    fn acknowledge(&mut self, sequence: u32) {
+        while self.in_flight.front().map_or(false, |s| s.sequence <= sequence) {
+            let snapshot = self.in_flight.pop_front().unwrap();
+            for change in &snapshot.changes {
+                match *change {
+                    Replication::Spawn(e, ref state) => {
+                        self.acked.insert(e, state.clone());
+                    },
+                    Replication::Despawn(e) | Replication::Destroyed(e) => {
+                        self.acked.remove(&e);
+                    },
+                    _ => {
+                        self.acked.get_mut(&change.entity()).map(|state| state.apply(change));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether each entity spawned or despawned in a snapshot the client hasn't acknowledged
+    /// yet, as of the latest one.
+    fn pending_presence(&self) -> HashMap<Entity, bool> {
+        let mut presence = HashMap::new();
+        for snapshot in &self.in_flight {
+            for change in &snapshot.changes {
+                match *change {
+                    Replication::Spawn(e, _) => { presence.insert(e, true); },
+                    Replication::Despawn(e) | Replication::Destroyed(e) => { presence.insert(e, false); },
+                    _ => (),
+                }
+            }
+        }
+        presence
+    }
+}
+
+fn encoded_size(change: &Replication) -> usize {
+    json::encode(change).map(|s| s.len() + 1).unwrap_or(0)
+}
+
+/// Tells each client about the entities near its view: spawning them as they come into range,
+/// sending what changed while they stay, and despawning them when they leave. Each client gets a
+/// limited number of bytes per update, spent on the most important changes first.
+pub struct ReplicationSystem {
+    commands: Sender<NetworkCommand>,
+    clients: HashMap<u16, ClientReplication>,
+    interval: Duration,
+    since_last: Duration,
+    cell_size: f32,
+    margin: f32,
+    max_view_extent: f32,
+    /// Views are cut down to the map, so far out coordinates can't blow up the spatial grid
+    map_min: Point2<f32>,
+    map_max: Point2<f32>,
+    bytes_per_update: usize,
+    report_interval: Option<Duration>,
+    since_report: Duration,
+}
+
+impl ReplicationSystem {
+    pub fn new(cfg: &ServerConfig, commands: Sender<NetworkCommand>) -> ReplicationSystem {
+        let updates_per_sec = 1000.0/(cfg.replication_interval.num_milliseconds().max(1) as f32);
+        ReplicationSystem {
+            commands: commands,
+            clients: HashMap::new(),
+            interval: cfg.replication_interval,
+            since_last: Duration::zero(),
+            cell_size: cfg.interest_cell_size,
+            margin: cfg.interest_margin,
+            max_view_extent: cfg.max_view_extent,
+            map_min: cfg.map_min,
+            map_max: cfg.map_max,
+            bytes_per_update: (cfg.replication_bytes_per_sec as f32/updates_per_sec) as usize,
+            report_interval: cfg.replication_report_interval,
+            since_report: Duration::zero(),
+        }
+    }
+
+    fn report(&self, players: &Players) {
+        for (id, client) in &self.clients {
+            let stats = &client.stats;
+            let name = players.0.get(id).map_or("", |n| n.as_str());
+            println!("replication to client {} ({}): {} bytes last update, {:.0} average, {} for full state, {} changes deferred",
+                     id, name, stats.last_update_bytes, stats.average_update_bytes, stats.full_state_bytes, stats.deferred_changes);
+        }
+    }
+}
+
+impl System<Message, ServerSystemContext> for ReplicationSystem {
+    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, ctx: ServerSystemContext) {
+        let (entities, movement, stats, control, owners, views, acks, players, mut bandwidth) = arg.fetch(|w| {
+            (
+                w.entities(),
+                w.read::<Movement>(),
+                w.read::<MovementStats>(),
+                w.read::<Controllable>(),
+                w.read::<Owner>(),
+                w.read_resource::<ClientViews>(),
+                w.read_resource::<SnapshotAcks>(),
+                w.read_resource::<Players>(),
+                w.write_resource::<Bandwidth>(),
+            )
+        });
+
+        self.since_last = self.since_last + ctx.dt;
+        if self.since_last < self.interval {
+            return;
+        }
+        self.since_last = Duration::zero();
+
+        self.clients.retain(|id, _| players.0.contains_key(id));
+
+        let mut grid = SpatialGrid::new(self.cell_size);
+        for (e, m) in (&entities, &movement).iter() {
+            grid.insert(e, m.position);
+        }
+
+        // working out the full state means encoding every visible entity, so only do it for
+        // the reports that print it
+        let reporting = match self.report_interval {
+            Some(interval) => {
+                self.since_report = self.since_report + self.interval;
+                self.since_report >= interval
+            },
+            None => false,
+        };
+
+        let margin = self.margin;
+        let default_stats = MovementStats::default();
+        for (&id, area) in &views.0 {
+            let area = area.within(self.map_min, self.map_max).clamped(self.max_view_extent);
+            let center = Point2::new((area.min.x + area.max.x)/2.0, (area.min.y + area.max.y)/2.0);
+            let client = self.clients.entry(id).or_insert_with(ClientReplication::new);
+
+            if let Some(&sequence) = acks.0.get(&id) {
+                client.acknowledge(sequence);
+            }
+            if client.in_flight.len() > MAX_IN_FLIGHT {
+                // the connection delivers in order, so it will get them eventually. this just
+                // keeps us from holding on to snapshots forever
+                println!("client {} is not acknowledging snapshots", id);
+                let latest = client.in_flight.back().unwrap().sequence;
+                client.acknowledge(latest);
+            }
+
+            let presence = client.pending_presence();
+            let mut known: HashSet<Entity> = client.acked.keys().cloned().filter(|e| presence.get(e) != Some(&false)).collect();
+            known.extend(presence.iter().filter(|&(_, &present)| present).map(|(&e, _)| e));
+
+            // entities have to get a bit closer to be spawned than to stay, so ones sitting on the
+            // edge of the view don't flicker in and out
+            let mut visible: HashSet<Entity> = known.iter().cloned().filter(|&e| {
+                movement.get(e).map_or(false, |m| area.contains(&m.position, 2.0*margin))
+            }).collect();
+            visible.extend(grid.query(&area, margin));
+
+            // (priority, changes), lowest priority value goes first
+            let mut candidates: Vec<(f32, Vec<Replication>)> = Vec::new();
+            for &e in known.difference(&visible) {
+                if movement.get(e).is_some() {
+                    candidates.push((-1.0, vec![Replication::Despawn(e)]));
+                }
+            }
+            for &e in &client.sent {
+                if movement.get(e).is_none() {
+                    candidates.push((-1.0, vec![Replication::Destroyed(e)]));
+                }
+            }
+
+            let mut full_state_bytes = 0;
+            for &e in &visible {
+                let m = movement.get(e).unwrap();
+                // as far as each client is concerned, only its own entities can be controlled
+                let controllable = control.get(e).is_some() && owners.get(e).map_or(false, |o| players.0.get(&id) == Some(&o.player));
+                let current = EntityState::new(m, stats.get(e).unwrap_or(&default_stats), controllable);
+                if reporting {
+                    full_state_bytes += encoded_size(&Replication::Spawn(e, current.clone()));
+                }
+
+                // anything despawned since the acknowledged state has to be spawned again
+                let baseline = match presence.get(&e) {
+                    Some(&false) => None,
+                    _ => client.acked.get(&e),
+                };
+                let changes = match baseline {
+                    Some(baseline) => current.delta_from(e, baseline),
+                    None => vec![Replication::Spawn(e, current)],
+                };
+                if changes.is_empty() {
+                    continue;
+                }
+
+                // nearest first, with entities players can control ahead of the rest
+                let mut priority = nalgebra::distance(&center, &Point2::new(m.position.x, m.position.y));
+                if !controllable {
+                    priority += self.max_view_extent;
+                }
+                candidates.push((priority, changes));
+            }
+            candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+
+            let mut changes = Vec::new();
+            let mut bytes = 0;
+            let mut deferred = 0;
+            for (_, entity_changes) in candidates {
+                let size: usize = entity_changes.iter().map(encoded_size).sum();
+                // always send something, even if one entity is bigger than the whole budget
+                if bytes + size > self.bytes_per_update && !changes.is_empty() {
+                    deferred += 1;
+                    continue;
+                }
+                bytes += size;
+                changes.extend(entity_changes);
+            }
+
+            for change in &changes {
+                match *change {
+                    Replication::Spawn(e, _) => { client.sent.insert(e); },
+                    Replication::Destroyed(e) => { client.sent.remove(&e); },
+                    _ => (),
+                }
+            }
+
+            client.stats.last_update_bytes = bytes;
+            client.stats.average_update_bytes = (client.stats.average_update_bytes*7.0 + bytes as f32)/8.0;
+            if reporting {
+                client.stats.full_state_bytes = full_state_bytes;
+            }
+            client.stats.deferred_changes = deferred;
+
+            if changes.is_empty() {
+                continue;
+            }
+            let snapshot = Snapshot {
+                sequence: client.next_sequence,
+                changes: changes,
+            };
+            client.next_sequence = client.next_sequence.wrapping_add(1);
+            let _ = self.commands.send(NetworkCommand::Send(id, NetworkMessage::Replicate(snapshot.clone())));
+            client.in_flight.push_back(snapshot);
+        }
+
+        bandwidth.0 = self.clients.iter().map(|(&id, c)| (id, c.stats.clone())).collect();
+
+        if reporting {
+            self.since_report = Duration::zero();
+            self.report(&players);
+        }
+    }
+
+    fn handle_message(&mut self, _: &mut World, _: &Message) { }
+}
@@ -0,0 +1,186 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use specs::{Entity, Join, MessageQueue, RunArg, System, World};
+
+use nalgebra;
+use nalgebra::{Norm, Point3, Vector3};
+
+use server::{ServerConfig, ServerSystemContext};
+use server::navigation::NavGrid;
+
+use common::Message;
+use common::components::{Movement, MovementStats, Obstacle, Steering};
+
+/// `count` open spots around `target`, `spacing` apart on a square grid, nearest first.
+pub fn formation_slots(target: Point3<f32>, count: usize, spacing: f32, grid: &NavGrid) -> Vec<Point3<f32>> {
+    let mut slots = Vec::with_capacity(count);
+    // give up eventually if everything nearby is blocked
+    let max_ring = count as i32 + 2;
+    let mut ring = 0;
+    while slots.len() < count && ring <= max_ring {
+        let mut candidates = Vec::new();
+        for dx in -ring..ring+1 {
+            for dy in -ring..ring+1 {
+                if dx.abs() == ring || dy.abs() == ring {
+                    candidates.push(Point3::new(target.x + dx as f32*spacing, target.y + dy as f32*spacing, target.z));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| {
+            let da = nalgebra::distance(a, &target);
+            let db = nalgebra::distance(b, &target);
+            da.partial_cmp(&db).unwrap_or(Ordering::Equal)
+        });
+        slots.extend(candidates.into_iter().filter(|p| !grid.is_blocked(p)).take(count - slots.len()));
+        ring += 1;
+    }
+    slots
+}
+
+/// Which of `slots` each of `positions` should go to, or None if there weren't enough slots.
+/// Closest pairs are matched first, so nobody walks across the formation to get to their spot.
+pub fn assign_slots(positions: &[Point3<f32>], slots: &[Point3<f32>]) -> Vec<Option<usize>> {
+    let mut pairs = Vec::with_capacity(positions.len()*slots.len());
+    for (i, position) in positions.iter().enumerate() {
+        for (s, slot) in slots.iter().enumerate() {
+            pairs.push((nalgebra::distance(position, slot), i, s));
+        }
+    }
+    pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    let mut assigned = vec![None; positions.len()];
+    let mut taken = HashSet::new();
+    for (_, i, s) in pairs {
+        if assigned[i].is_some() || taken.contains(&s) {
+            continue;
+        }
+        assigned[i] = Some(s);
+        taken.insert(s);
+    }
+    assigned
+}
+
+/// Where an entity is and where it's heading.
+struct Agent {
+    entity: Entity,
+    position: Vector3<f32>,
+    velocity: Vector3<f32>,
+    moving: bool,
+}
+
+/// Keeps groups of moving boxes from piling up: moving entities steer away from each other, and
+/// aside when about to run into something. An entity close to its destination stops when someone
+/// has already settled there.
+pub struct SteeringSystem {
+    spacing: f32,
+    separation_radius: f32,
+    avoidance_horizon: f32,
+    arrival_radius: f32,
+}
+
+impl SteeringSystem {
+    pub fn new(cfg: &ServerConfig) -> SteeringSystem {
+        SteeringSystem {
+            spacing: cfg.formation_spacing,
+            separation_radius: cfg.separation_radius,
+            avoidance_horizon: cfg.avoidance_horizon.num_milliseconds() as f32,
+            arrival_radius: cfg.arrival_radius,
+        }
+    }
+
+    /// Separation from close neighbours, plus a sidestep for anyone we're about to run into.
+    fn steer(&self, agent: &Agent, agents: &[Agent]) -> Vector3<f32> {
+        let mut steer: Vector3<f32> = nalgebra::zero();
+        for other in agents {
+            if other.entity == agent.entity {
+                continue;
+            }
+
+            let away = agent.position - other.position;
+            let distance = away.norm();
+            if distance > 0.0 && distance < self.separation_radius {
+                steer = steer + away*((self.separation_radius - distance)/(self.separation_radius*distance));
+            }
+
+            // where the other will be relative to us at the closest point of approach
+            let relative_velocity = other.velocity - agent.velocity;
+            let speed_sq = relative_velocity.norm_squared();
+            if speed_sq == 0.0 {
+                continue;
+            }
+            let relative_position = other.position - agent.position;
+            let t = -nalgebra::dot(&relative_position, &relative_velocity)/speed_sq;
+            if t <= 0.0 || t > self.avoidance_horizon {
+                continue;
+            }
+            let closest = relative_position + relative_velocity*t;
+            let miss = closest.norm();
+            if miss < self.separation_radius {
+                // head for whichever side we'd already pass on. dead on, pick one consistently
+                let side = if miss > 0.0 { -closest/miss } else { Vector3::new(-relative_velocity.y, relative_velocity.x, 0.0)/speed_sq.sqrt() };
+                steer = steer + side*(1.0 - t/self.avoidance_horizon);
+            }
+        }
+        steer
+    }
+}
+
+impl System<Message, ServerSystemContext> for SteeringSystem {
+    fn run(&mut self, arg: RunArg, _: MessageQueue<Message>, ctx: ServerSystemContext) {
+        let (entities, mut movement, mut steering, stats, obstacles, grid) = arg.fetch(|w| {
+            (w.entities(), w.write::<Movement>(), w.write::<Steering>(), w.read::<MovementStats>(), w.read::<Obstacle>(), w.read_resource::<NavGrid>())
+        });
+
+        let mut movers: Vec<(Entity, &mut Movement)> = (&entities, &mut movement).iter()
+            .filter(|&(e, _)| obstacles.get(e).is_none())
+            .collect();
+        for &(e, _) in &movers {
+            if steering.get(e).is_none() {
+                steering.insert(e, Steering::new());
+            }
+        }
+
+        let agents: Vec<Agent> = movers.iter().map(|&(e, ref m)| {
+            Agent { entity: e, position: m.position.to_vector(), velocity: m.velocity, moving: !m.path.is_empty() }
+        }).collect();
+
+        let default_stats = MovementStats::default();
+        let dt = ctx.dt.num_milliseconds() as f32;
+        for (agent, &mut (e, ref mut m)) in agents.iter().zip(movers.iter_mut()) {
+            let steer = steering.get_mut(e).unwrap();
+            steer.desired = nalgebra::zero();
+            if !agent.moving {
+                continue;
+            }
+
+            // close enough, and someone else already got there
+            if m.path.waypoints.len() == 1 {
+                let target = *m.path.next().unwrap();
+                let settled = agents.iter().any(|other| {
+                    !other.moving && nalgebra::distance(&other.position.to_point(), &target) < self.spacing*0.5
+                });
+                if settled && nalgebra::distance(&m.position, &target) < self.arrival_radius {
+                    m.path.clear();
+                    continue;
+                }
+            }
+
+            // never ask for more than half our top speed, so we still get where we're going
+            let away = self.steer(agent, &agents);
+            let length = away.norm();
+            if length == 0.0 {
+                continue;
+            }
+            let max_speed = stats.get(e).unwrap_or(&default_stats).max_speed;
+            let desired = away*(length.min(1.0)/length)*(max_speed*0.5);
+            let desired = Vector3::new(desired.x, desired.y, 0.0);
+            // the movement system eases into it, so don't ask to go where we can't
+            if !grid.is_blocked(&(m.position + desired*dt)) {
+                steer.desired = desired;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, _: &mut World, _: &Message) { }
+}
@@ -0,0 +1,776 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration as StdDuration, Instant};
+
+use specs::{Entity, Planner, World};
+
+use nalgebra::{Point2, Point3};
+
+use server::{ServerConfig, ServerSystemContext};
+use server::connections::{NetworkCommand, NetworkEvent, NetworkServer};
+use server::systems::ReplicationSystem;
+
+use common::{ConnectRequest, Message, NetworkMessage, ProtocolRange, ServerInfo, ViewArea};
+use common::replication::Replication;
+use common::resources::{Bandwidth, ClientViews, Players, SnapshotAcks};
+use common::discovery::DiscoveryResponder;
+use common::net::Connection;
+use common::testing::TempFile;
+
+fn test_info() -> ServerInfo {
+    ServerInfo {
+        name: "test server".to_owned(),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        protocols: ProtocolRange::supported(),
+        player_count: 3,
+        capacity: 8,
+        uptime_secs: 10,
+    }
+}
+
+/// Settings for a server on a free loopback port that discovery can't find
+fn test_config() -> ServerConfig {
+    let mut cfg = ServerConfig::new();
+    cfg.server_address = "127.0.0.1:0".parse().unwrap();
+    cfg.discovery_port = None;
+    cfg
+}
+
+/// Like `test_config`, but on a port picked up front, for when the address has to be known before
+/// the server starts
+fn fixed_port_config() -> ServerConfig {
+    let mut cfg = test_config();
+    cfg.server_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    cfg
+}
+
+/// Start a network thread, returning its address, the simulation's ends of its channels and the
+/// thread itself
+fn start_network(cfg: ServerConfig) -> (SocketAddr, Sender<NetworkCommand>, Receiver<NetworkEvent>, JoinHandle<()>) {
+    let (event_tx, event_rx) = channel();
+    let (command_tx, command_rx) = channel();
+    let server = NetworkServer::new(cfg, event_tx);
+    let address = server.local_addr().unwrap();
+    let thread = thread::spawn(move || server.run(command_rx));
+    (address, command_tx, event_rx, thread)
+}
+
+fn stop_network(commands: Sender<NetworkCommand>, thread: JoinHandle<()>) {
+    commands.send(NetworkCommand::Shutdown).unwrap();
+    thread.join().unwrap();
+}
+
+/// A blocking plaintext connection to `address`
+fn open_connection(address: SocketAddr) -> Connection {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(StdDuration::from_millis(500))).unwrap();
+    Connection::plaintext(stream)
+}
+
+/// The next message from the server, or None if the connection closes or goes quiet
+fn receive_one(connection: &mut Connection) -> Option<NetworkMessage> {
+    for _ in 0..10 {
+        if let Some(msg) = connection.next_message() {
+            return Some(msg.unwrap());
+        }
+        if connection.receive().is_err() {
+            return None;
+        }
+    }
+    None
+}
+
+/// Connect and finish the handshake as `name`
+fn join(address: SocketAddr, name: &str) -> Connection {
+    let mut connection = open_connection(address);
+    connection.send(&NetworkMessage::Connect(ConnectRequest::new(name.to_owned(), None))).unwrap();
+    match receive_one(&mut connection) {
+        Some(NetworkMessage::Accepted(_)) => connection,
+        other => panic!("expected {} to be accepted, got {:?}", name, other),
+    }
+}
+
+/// Skip messages from the server until one is `wanted`. False if none is within a few seconds.
+fn wait_for_message<F: Fn(&NetworkMessage) -> bool>(connection: &mut Connection, wanted: F) -> bool {
+    let deadline = Instant::now() + StdDuration::from_secs(3);
+    while Instant::now() < deadline {
+        match receive_one(connection) {
+            Some(ref msg) if wanted(msg) => return true,
+            Some(_) => (),
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Skip events from the network thread until one is `wanted`. False if none is within a few
+/// seconds.
+fn wait_for_event<F: Fn(&NetworkEvent) -> bool>(events: &Receiver<NetworkEvent>, wanted: F) -> bool {
+    let deadline = Instant::now() + StdDuration::from_secs(3);
+    while Instant::now() < deadline {
+        match events.recv_timeout(StdDuration::from_millis(100)) {
+            Ok(ref event) if wanted(event) => return true,
+            Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+    false
+}
+
+/// Send a line the server can't decode
+fn send_garbage(connection: &Connection) {
+    let mut stream = connection.stream();
+    stream.write_all(b"not a message\n").unwrap();
+}
+
+#[test]
+fn discovery_responder_answers_queries() {
+    use rustc_serialize::json;
+    use std::net::UdpSocket;
+    use common::discovery::DiscoveryMessage;
+
+    let responder = DiscoveryResponder::new(0).unwrap();
+    let target = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), responder.local_addr().unwrap().port());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(StdDuration::from_millis(500))).unwrap();
+    socket.send_to(json::encode(&DiscoveryMessage::Query).unwrap().as_bytes(), target).unwrap();
+
+    thread::sleep(StdDuration::from_millis(50));
+    responder.respond(&test_info(), "127.0.0.1:8844".parse().unwrap());
+
+    let mut buf = [0u8; 2048];
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    match json::decode(&String::from_utf8_lossy(&buf[..len])).unwrap() {
+        DiscoveryMessage::Announce(info, port) => {
+            assert_eq!(info.name, "test server");
+            assert_eq!(port, 8844);
+        }
+        other => panic!("expected an announcement, got {:?}", other),
+    }
+}
+
+#[test]
+fn loopback_games_are_only_advertised_on_loopback() {
+    use common::discovery::reachable;
+
+    let loopback: SocketAddr = "127.0.0.1:8844".parse().unwrap();
+    let everywhere: SocketAddr = "0.0.0.0:8844".parse().unwrap();
+    let local_peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+    let lan_peer: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+
+    assert!(reachable(&loopback, &local_peer));
+    assert!(!reachable(&loopback, &lan_peer));
+    assert!(reachable(&everywhere, &local_peer));
+    assert!(reachable(&everywhere, &lan_peer));
+}
+
+#[cfg(feature = "client")]
+#[test]
+fn lan_discovery_on_loopback() {
+    use common::discovery::discover_servers;
+
+    let responder = DiscoveryResponder::new(0).unwrap();
+    let target = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), responder.local_addr().unwrap().port());
+
+    let server = thread::spawn(move || {
+        for _ in 0..100 {
+            responder.respond(&test_info(), "127.0.0.1:8844".parse().unwrap());
+            thread::sleep(StdDuration::from_millis(5));
+        }
+    });
+
+    let servers = discover_servers(target, StdDuration::from_millis(300)).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].address, "127.0.0.1:8844".parse().unwrap());
+    assert_eq!(servers[0].info.name, "test server");
+    assert_eq!(servers[0].info.player_count, 3);
+}
+
+#[test]
+fn user_list_authentication() {
+    use std::fs::File;
+    use server::{Authenticator, UserList};
+
+    let file = TempFile::new("user_list_authentication");
+    File::create(&file.0).unwrap().write_all(b"# name password\nalice hunter2\n\nbob  correct horse\n").unwrap();
+
+    let users = UserList::load(&file.0).unwrap();
+    assert!(users.authenticate("alice", Some("hunter2")));
+    assert!(users.authenticate("bob", Some("correct horse")));
+    assert!(!users.authenticate("alice", Some("wrong")));
+    assert!(!users.authenticate("alice", None));
+    assert!(!users.authenticate("mallory", Some("hunter2")));
+}
+
+#[test]
+fn rate_limiter_refills_over_time() {
+    use time::Duration;
+    use server::ratelimit::RateLimiter;
+
+    let mut limit = RateLimiter::new(2.0, 1.0);
+    assert!(limit.try_take(1.0));
+    assert!(limit.try_take(1.0));
+    assert!(!limit.try_take(1.0));
+
+    limit.refill(Duration::milliseconds(500));
+    assert!(!limit.try_take(1.0));
+    limit.refill(Duration::milliseconds(500));
+    assert!(limit.try_take(1.0));
+
+    // never refills past capacity
+    limit.refill(Duration::seconds(10));
+    assert!(limit.try_take(2.0));
+    assert!(!limit.try_take(1.0));
+
+    // going over what's left still uses it up
+    limit.refill(Duration::seconds(1));
+    assert!(!limit.charge(3.0));
+    assert!(!limit.try_take(1.0));
+}
+
+#[test]
+fn spatial_grid_finds_only_nearby_items() {
+    use nalgebra::{Point2, Point3};
+    use common::ViewArea;
+    use server::interest::SpatialGrid;
+
+    let mut grid = SpatialGrid::new(10.0);
+    grid.insert(1, Point3::new(0.0, 0.0, 0.0));
+    grid.insert(2, Point3::new(-12.0, 3.0, 0.0)); // in a neighbouring cell, just outside the view
+    grid.insert(3, Point3::new(500.0, 500.0, 0.0));
+
+    let view = ViewArea::new(Point2::new(-10.0, -10.0), Point2::new(10.0, 10.0));
+    assert_eq!(grid.query(&view, 0.0), vec![1]);
+
+    let mut found = grid.query(&view, 5.0);
+    found.sort();
+    assert_eq!(found, vec![1, 2]);
+}
+
+#[test]
+fn nav_grid_paths_around_obstacles() {
+    use nalgebra::{Point2, Point3, Vector2};
+    use server::navigation::NavGrid;
+
+    let mut grid = NavGrid::new(Point2::new(-10.0, -10.0), Point2::new(10.0, 10.0), 0.5);
+    // a wall across the middle with a gap at the right end
+    grid.block(Point2::new(-2.0, 0.0), Vector2::new(8.0, 0.5));
+
+    let start = Point3::new(0.0, -5.0, 0.0);
+    let goal = Point3::new(0.0, 5.0, 0.0);
+    let path = grid.find_path(start, goal).unwrap();
+    assert_eq!(*path.last().unwrap(), goal);
+    assert!(path.len() > 1);
+
+    // no leg of the path crosses the wall
+    let mut from = start;
+    for to in &path {
+        for i in 0..101 {
+            let p = from + (*to - from)*(i as f32/100.0);
+            assert!(!grid.is_blocked(&p), "{:?} -> {:?} passes through {:?}", from, to, p);
+        }
+        from = *to;
+    }
+
+    // straight there when nothing is in the way
+    assert_eq!(grid.find_path(start, Point3::new(3.0, -8.0, 0.0)).unwrap(), vec![Point3::new(3.0, -8.0, 0.0)]);
+
+    // walled in completely
+    grid.block(Point2::new(0.0, 0.0), Vector2::new(20.0, 0.5));
+    assert!(grid.find_path(start, goal).is_none());
+}
+
+#[test]
+fn penetration_pushes_along_the_shallow_axis() {
+    use nalgebra::Vector2;
+    use server::systems::penetration;
+
+    let unit = Vector2::new(1.0, 1.0);
+    let origin = Vector2::new(0.0, 0.0);
+
+    assert_eq!(penetration(&origin, &unit, &Vector2::new(3.0, 0.0), &unit), None);
+    assert_eq!(penetration(&origin, &unit, &Vector2::new(2.0, 0.0), &unit), None); // just touching
+
+    assert_eq!(penetration(&origin, &unit, &Vector2::new(1.5, 0.5), &unit), Some(Vector2::new(0.5, 0.0)));
+    assert_eq!(penetration(&origin, &unit, &Vector2::new(-0.5, -1.75), &unit), Some(Vector2::new(0.0, -0.25)));
+}
+
+#[test]
+fn formation_slots_are_spread_out_and_open() {
+    use nalgebra::{self, Point2, Point3, Vector2};
+    use server::navigation::NavGrid;
+    use server::systems::formation_slots;
+
+    let mut grid = NavGrid::new(Point2::new(-20.0, -20.0), Point2::new(20.0, 20.0), 0.5);
+    // everything to the right of the target is blocked
+    grid.block(Point2::new(10.0, 0.0), Vector2::new(8.0, 20.0));
+
+    let target = Point3::new(0.0, 0.0, 0.0);
+    let slots = formation_slots(target, 9, 2.5, &grid);
+    assert_eq!(slots.len(), 9);
+    assert_eq!(slots[0], target);
+    for (i, a) in slots.iter().enumerate() {
+        assert!(!grid.is_blocked(a));
+        for b in &slots[i+1..] {
+            assert!(nalgebra::distance(a, b) >= 2.5 - 1.0e-4);
+        }
+    }
+}
+
+#[test]
+fn group_members_take_the_nearest_free_slots() {
+    use nalgebra::Point3;
+    use server::systems::assign_slots;
+
+    let slots = [Point3::new(-1.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0)];
+    let positions = [Point3::new(10.0, 0.0, 0.0), Point3::new(-10.0, 0.0, 0.0), Point3::new(-11.0, 0.0, 0.0)];
+
+    // nobody crosses over, and whoever's furthest misses out
+    assert_eq!(assign_slots(&positions, &slots), vec![Some(1), Some(0), None]);
+}
+
+#[test]
+fn orders_for_someone_elses_entities_are_ignored() {
+    use specs::{System, World};
+    use nalgebra::{Point2, Point3};
+    use common::Message;
+    use common::components::{Controllable, Movement, Owner};
+    use common::resources::{CurrentHover, Players};
+    use server::navigation::NavGrid;
+    use server::systems::MovementSystem;
+
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.register::<Controllable>();
+    world.register::<Owner>();
+    world.add_resource(NavGrid::new(Point2::new(-20.0, -20.0), Point2::new(20.0, 20.0), 0.5));
+    let players = vec![(0, "first".to_owned()), (1, "second".to_owned())];
+    world.add_resource(Players(players.into_iter().collect()));
+    let first = world.create_now().with(Movement::new_pos(Point3::new(-5.0, 0.0, 0.0))).with(Controllable::new()).with(Owner::new("first".to_owned())).build();
+    let second = world.create_now().with(Movement::new_pos(Point3::new(5.0, 0.0, 0.0))).with(Controllable::new()).with(Owner::new("second".to_owned())).build();
+
+    // the second client claims to be the first, but the server knows better
+    let mut system = MovementSystem::new(&ServerConfig::new());
+    let target = CurrentHover::Ground(Point3::new(0.0, 10.0, 0.0));
+    let order = Message::CommandGroup(0, vec![first, second], target.clone(), false).sent_by(1);
+    system.handle_message(&mut world, &order);
+    assert!(world.read::<Movement>().get(first).unwrap().path.is_empty());
+    assert!(!world.read::<Movement>().get(second).unwrap().path.is_empty());
+
+    let order = Message::InteractWith(0, first, target).sent_by(1);
+    system.handle_message(&mut world, &order);
+    assert!(world.read::<Movement>().get(first).unwrap().path.is_empty());
+}
+
+#[test]
+fn players_keep_their_entities_whatever_connection_they_get() {
+    use specs::Join;
+    use time::Duration;
+    use common::components::{Movement, Owner};
+    use common::resources::CurrentHover;
+    use server::{make_server_world, ServerGame};
+
+    let cfg = fixed_port_config();
+    let mut game = ServerGame::new(make_server_world(cfg.clone()), cfg.clone());
+
+    // the query takes up a connection id before the player joins
+    let mut query = open_connection(cfg.server_address);
+    query.send(&NetworkMessage::ServerInfoRequest).unwrap();
+    assert!(wait_for_message(&mut query, |msg| match *msg {
+        NetworkMessage::ServerInfoResponse(_) => true,
+        _ => false,
+    }));
+    let mut client = join(cfg.server_address, "player");
+
+    let e = {
+        let world = game.planner.mut_world();
+        let entities = world.entities();
+        let owners = world.read::<Owner>();
+        let found = (&entities, &owners).iter().find(|&(_, o)| o.player == "player").map(|(e, _)| e);
+        found.unwrap()
+    };
+    let order = Message::CommandGroup(0, vec![e], CurrentHover::Ground(Point3::new(5.0, -5.0, 0.0)), false);
+    client.send(&NetworkMessage::GameMessage(order)).unwrap();
+
+    let deadline = Instant::now() + StdDuration::from_secs(3);
+    let mut moving = false;
+    while !moving && Instant::now() < deadline {
+        game.run(Duration::milliseconds(20));
+        moving = !game.planner.mut_world().read::<Movement>().get(e).unwrap().path.is_empty();
+        thread::sleep(StdDuration::from_millis(10));
+    }
+    assert!(moving);
+    game.shutdown(None).unwrap();
+}
+
+#[test]
+fn outdated_clients_are_rejected() {
+    use common::{RejectReason, PROTOCOL_VERSION};
+
+    let (address, commands, _events, thread) = start_network(test_config());
+    let mut connection = open_connection(address);
+
+    let old = ProtocolRange { min: 1, max: PROTOCOL_VERSION - 1 };
+    assert!(!ProtocolRange::supported().contains(old.max));
+    let mut request = ConnectRequest::new("old timer".to_owned(), None);
+    request.protocols = old;
+    connection.send(&NetworkMessage::Connect(request)).unwrap();
+
+    match receive_one(&mut connection) {
+        Some(NetworkMessage::Rejected(RejectReason::Version(ours, theirs))) => {
+            assert_eq!(ours, ProtocolRange::supported());
+            assert_eq!(theirs, old);
+        }
+        other => panic!("expected a version rejection, got {:?}", other),
+    }
+    stop_network(commands, thread);
+}
+
+#[test]
+fn repeated_violations_disconnect_but_a_slip_does_not() {
+    let mut cfg = test_config();
+    cfg.violation_burst = 3;
+    let (address, commands, events, thread) = start_network(cfg);
+
+    let mut careful = join(address, "careful");
+    send_garbage(&careful);
+    careful.send(&NetworkMessage::Ping(1)).unwrap();
+    assert!(wait_for_message(&mut careful, |msg| match *msg {
+        NetworkMessage::Pong(1) => true,
+        _ => false,
+    }));
+
+    let mut rude = join(address, "rude");
+    for _ in 0..5 {
+        send_garbage(&rude);
+    }
+    assert!(wait_for_message(&mut rude, |msg| match *msg {
+        NetworkMessage::Disconnect(_) => true,
+        _ => false,
+    }));
+    assert!(wait_for_event(&events, |event| match *event {
+        NetworkEvent::Disconnected(id) => id == 1,
+        _ => false,
+    }));
+
+    // the careful one is still around
+    careful.send(&NetworkMessage::Ping(2)).unwrap();
+    assert!(wait_for_message(&mut careful, |msg| match *msg {
+        NetworkMessage::Pong(2) => true,
+        _ => false,
+    }));
+    stop_network(commands, thread);
+}
+
+#[test]
+fn slow_consumers_are_dropped() {
+    use std::io;
+
+    let mut cfg = test_config();
+    cfg.max_queued_bytes = 64*1024;
+    let (address, commands, events, thread) = start_network(cfg);
+
+    // joins, then never reads again. far more is sent than the socket buffers can hold
+    let slow = join(address, "slow");
+    let filler = "x".repeat(256*1024);
+    for _ in 0..128 {
+        commands.send(NetworkCommand::Send(0, NetworkMessage::Motd(filler.clone()))).unwrap();
+    }
+    assert!(wait_for_event(&events, |event| match *event {
+        NetworkEvent::Disconnected(id) => id == 0,
+        _ => false,
+    }));
+
+    // what made it into the socket buffers can still be read, then the connection ends
+    let mut stream = slow.stream();
+    match io::copy(&mut stream, &mut io::sink()) {
+        Ok(_) => (),
+        Err(error) => assert_eq!(error.kind(), io::ErrorKind::ConnectionReset),
+    }
+    stop_network(commands, thread);
+}
+
+#[test]
+fn shutdown_says_goodbye_and_saves_the_world() {
+    use specs::{Join, World};
+    use common::components::{Collider, Controllable, Movement, MovementStats, Obstacle, Owner};
+    use server::{load_world, make_server_world, ServerGame};
+
+    let cfg = fixed_port_config();
+    let mut game = ServerGame::new(make_server_world(cfg.clone()), cfg.clone());
+    let mut client = join(cfg.server_address, "leaving");
+    let entities = (&game.planner.mut_world().read::<Movement>()).iter().count();
+
+    game.stop();
+    assert!(!game.is_running());
+    let saved = TempFile::new("shutdown_says_goodbye_and_saves_the_world");
+    game.shutdown(Some(&saved.0)).unwrap();
+
+    // the goodbye was flushed before shutdown returned, then the socket was closed
+    assert!(wait_for_message(&mut client, |msg| match *msg {
+        NetworkMessage::Disconnect(_) => true,
+        _ => false,
+    }));
+    assert!(receive_one(&mut client).is_none());
+    assert!(TcpStream::connect(cfg.server_address).is_err());
+
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.register::<Controllable>();
+    world.register::<Owner>();
+    world.register::<MovementStats>();
+    world.register::<Collider>();
+    world.register::<Obstacle>();
+    load_world(&mut world, &saved.0).unwrap();
+    assert_eq!((&world.read::<Movement>()).iter().count(), entities);
+}
+
+#[test]
+fn info_queries_and_motd_reloads() {
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    let motd = TempFile::new("info_queries_and_motd_reloads");
+    File::create(&motd.0).unwrap().write_all(b"first motd\n").unwrap();
+    let mut cfg = test_config();
+    cfg.name = "info test".to_owned();
+    cfg.motd_file = Some(motd.0.clone());
+    let (address, commands, _events, thread) = start_network(cfg);
+
+    // a query is answered without joining, then the connection is closed
+    let mut query = open_connection(address);
+    query.send(&NetworkMessage::ServerInfoRequest).unwrap();
+    match receive_one(&mut query) {
+        Some(NetworkMessage::ServerInfoResponse(info)) => {
+            assert_eq!(info.name, "info test");
+            assert_eq!(info.protocols, ProtocolRange::supported());
+            assert_eq!(info.player_count, 0);
+        }
+        other => panic!("expected server info, got {:?}", other),
+    }
+    assert!(receive_one(&mut query).is_none());
+
+    let mut first = join(address, "first");
+    assert!(wait_for_message(&mut first, |msg| match *msg {
+        NetworkMessage::Motd(ref text) => text == "first motd",
+        _ => false,
+    }));
+
+    // players can ask too, and count themselves
+    first.send(&NetworkMessage::ServerInfoRequest).unwrap();
+    assert!(wait_for_message(&mut first, |msg| match *msg {
+        NetworkMessage::ServerInfoResponse(ref info) => info.player_count == 1,
+        _ => false,
+    }));
+
+    // the next player sees the edited file. the modification time is moved on explicitly, in
+    // case the filesystem can't tell two writes this close together apart
+    let file = File::create(&motd.0).unwrap();
+    (&file).write_all(b"second motd\n").unwrap();
+    file.set_modified(SystemTime::now() + StdDuration::from_secs(10)).unwrap();
+    let mut second = join(address, "second");
+    assert!(wait_for_message(&mut second, |msg| match *msg {
+        NetworkMessage::Motd(ref text) => text == "second motd",
+        _ => false,
+    }));
+    stop_network(commands, thread);
+}
+
+#[test]
+fn chat_is_relayed_within_limits() {
+    use common::ChatMessage;
+
+    let mut cfg = test_config();
+    cfg.max_chat_length = 16;
+    cfg.chat_burst = 2;
+    cfg.chat_rate = 0.0;
+    let (address, commands, _events, thread) = start_network(cfg);
+
+    let mut talker = join(address, "talker");
+    let mut listener = join(address, "listener");
+    let is_chat = |msg: &NetworkMessage, from: Option<&str>, text: &str| match *msg {
+        NetworkMessage::Chat(ChatMessage { from: ref f, text: ref t }) => f.as_ref().map(|f| f.as_str()) == from && t == text,
+        _ => false,
+    };
+
+    // everyone hears it, control characters and all stripped out
+    talker.send(&NetworkMessage::SendChat("hel\u{7}lo".to_owned())).unwrap();
+    assert!(wait_for_message(&mut listener, |msg| is_chat(msg, Some("talker"), "hello")));
+    assert!(wait_for_message(&mut talker, |msg| is_chat(msg, Some("talker"), "hello")));
+
+    // too long, and only the sender is told
+    talker.send(&NetworkMessage::SendChat("x".repeat(17))).unwrap();
+    assert!(wait_for_message(&mut talker, |msg| is_chat(msg, None, "chat messages can be at most 16 characters")));
+
+    // the burst is used up after one more
+    talker.send(&NetworkMessage::SendChat("again".to_owned())).unwrap();
+    talker.send(&NetworkMessage::SendChat("and again".to_owned())).unwrap();
+    assert!(wait_for_message(&mut talker, |msg| is_chat(msg, None, "you are sending chat messages too quickly")));
+
+    // the listener got exactly what was relayed, in order
+    assert!(wait_for_message(&mut listener, |msg| match *msg {
+        NetworkMessage::Chat(ref chat) => {
+            assert_eq!((chat.from.as_ref().map(|f| f.as_str()), chat.text.as_str()), (Some("talker"), "again"));
+            true
+        }
+        _ => false,
+    }));
+    listener.send(&NetworkMessage::Ping(1)).unwrap();
+    assert!(wait_for_message(&mut listener, |msg| match *msg {
+        NetworkMessage::Chat(_) => panic!("relayed something over the limits: {:?}", msg),
+        NetworkMessage::Pong(1) => true,
+        _ => false,
+    }));
+    stop_network(commands, thread);
+}
+
+/// A planner running only replication to client 0, which views `view`, and where its snapshots
+/// end up
+fn replication_planner(world: World, view: ViewArea) -> (Planner<Message, ServerSystemContext>, Receiver<NetworkCommand>) {
+    let (commands_tx, commands_rx) = channel();
+    let mut world = world;
+    world.add_resource(Players(vec![(0, "viewer".to_owned())].into_iter().collect()));
+    world.add_resource(ClientViews(vec![(0, view)].into_iter().collect()));
+    world.add_resource(SnapshotAcks(HashMap::new()));
+    world.add_resource(Bandwidth(HashMap::new()));
+
+    let mut planner = Planner::new(world, 1);
+    planner.add_system(ReplicationSystem::new(&ServerConfig::new(), commands_tx), "replication", 1);
+    (planner, commands_rx)
+}
+
+/// Run one replication update and return what client 0 was sent, acknowledging it straight away
+fn replicate(planner: &mut Planner<Message, ServerSystemContext>, commands: &Receiver<NetworkCommand>) -> Vec<Replication> {
+    let cfg = ServerConfig::new();
+    planner.dispatch(ServerSystemContext::new(cfg.replication_interval, cfg.timestep));
+    planner.handle_messages();
+    match commands.recv_timeout(StdDuration::from_secs(1)) {
+        Ok(NetworkCommand::Send(0, NetworkMessage::Replicate(snapshot))) => {
+            planner.mut_world().write_resource::<SnapshotAcks>().0.insert(0, snapshot.sequence);
+            snapshot.changes
+        }
+        other => panic!("expected a snapshot, got {:?}", other),
+    }
+}
+
+fn view(min_x: f32, min_y: f32, max_x: f32, max_y: f32) -> ViewArea {
+    ViewArea::new(Point2::new(min_x, min_y), Point2::new(max_x, max_y))
+}
+
+#[test]
+fn replication_follows_the_view() {
+    use common::components::{Controllable, Movement, MovementStats, Owner};
+
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.register::<MovementStats>();
+    world.register::<Controllable>();
+    world.register::<Owner>();
+    let near = world.create_now().with(Movement::new_pos(Point3::new(0.0, 0.0, 0.0))).build();
+    let far = world.create_now().with(Movement::new_pos(Point3::new(40.0, 40.0, 0.0))).build();
+    let (mut planner, commands) = replication_planner(world, view(-10.0, -10.0, 10.0, 10.0));
+
+    let spawned = |changes: &[Replication], e: Entity| changes.iter().any(|c| match *c {
+        Replication::Spawn(spawned, _) => spawned == e,
+        _ => false,
+    });
+    let despawned = |changes: &[Replication], e: Entity| changes.iter().any(|c| match *c {
+        Replication::Despawn(despawned) => despawned == e,
+        _ => false,
+    });
+
+    let changes = replicate(&mut planner, &commands);
+    assert!(spawned(&changes, near));
+    assert!(!spawned(&changes, far));
+
+    // looking elsewhere swaps one for the other
+    planner.mut_world().write_resource::<ClientViews>().0.insert(0, view(30.0, 30.0, 50.0, 50.0));
+    let changes = replicate(&mut planner, &commands);
+    assert!(spawned(&changes, far));
+    assert!(despawned(&changes, near));
+
+    // a view way off the map sees nothing, rather than overflowing the grid
+    planner.mut_world().write_resource::<ClientViews>().0.insert(0, view(4.0e10, 4.0e10, 4.0e10 + 10.0, 4.0e10 + 10.0));
+    let changes = replicate(&mut planner, &commands);
+    assert!(despawned(&changes, far));
+}
+
+#[test]
+fn entities_destroyed_out_of_view_are_reported() {
+    use common::components::{Controllable, Movement, MovementStats, Owner};
+
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.register::<MovementStats>();
+    world.register::<Controllable>();
+    world.register::<Owner>();
+    let doomed = world.create_now().with(Movement::new_pos(Point3::new(0.0, 0.0, 0.0))).build();
+    let (mut planner, commands) = replication_planner(world, view(-10.0, -10.0, 10.0, 10.0));
+
+    let destroyed = |changes: &[Replication]| changes.iter().any(|c| match *c {
+        Replication::Destroyed(e) => e == doomed,
+        _ => false,
+    });
+
+    replicate(&mut planner, &commands);
+    planner.mut_world().write_resource::<ClientViews>().0.insert(0, view(30.0, 30.0, 50.0, 50.0));
+    let changes = replicate(&mut planner, &commands);
+    assert!(changes.iter().any(|c| match *c {
+        Replication::Despawn(e) => e == doomed,
+        _ => false,
+    }));
+
+    // the client may still have it in a control group, so it has to hear about this
+    planner.mut_world().delete_now(doomed);
+    let changes = replicate(&mut planner, &commands);
+    assert!(destroyed(&changes));
+
+    // and only once
+    planner.mut_world().create_now().with(Movement::new_pos(Point3::new(40.0, 40.0, 0.0))).build();
+    let changes = replicate(&mut planner, &commands);
+    assert!(!destroyed(&changes));
+}
+
+#[test]
+fn orders_to_go_nowhere_are_ignored() {
+    use std::f32;
+    use specs::System;
+    use common::components::{Controllable, Movement, Owner};
+    use common::resources::CurrentHover;
+    use server::navigation::NavGrid;
+    use server::systems::{assign_slots, formation_slots, MovementSystem};
+
+    let mut world = World::new();
+    world.register::<Movement>();
+    world.register::<Controllable>();
+    world.register::<Owner>();
+    let grid = NavGrid::new(Point2::new(-20.0, -20.0), Point2::new(20.0, 20.0), 0.5);
+    world.add_resource(grid.clone());
+    world.add_resource(Players(vec![(0, "player".to_owned())].into_iter().collect()));
+    let group: Vec<Entity> = (0..3).map(|i| {
+        world.create_now().with(Movement::new_pos(Point3::new(i as f32, 0.0, 0.0))).with(Controllable::new()).with(Owner::new("player".to_owned())).build()
+    }).collect();
+
+    // json null decodes as NaN
+    let nowhere = CurrentHover::Ground(Point3::new(f32::NAN, 0.0, 0.0));
+    let mut system = MovementSystem::new(&ServerConfig::new());
+    system.handle_message(&mut world, &Message::CommandGroup(0, group.clone(), nowhere.clone(), false));
+    system.handle_message(&mut world, &Message::InteractWith(0, group[0], nowhere.clone()));
+    system.handle_message(&mut world, &Message::QueueInteraction(0, group[1], CurrentHover::Ground(Point3::new(0.0, f32::INFINITY, 0.0))));
+    for &e in &group {
+        let movement = world.read::<Movement>();
+        let m = movement.get(e).unwrap();
+        assert!(m.path.is_empty());
+        assert!(m.position.x.is_finite());
+    }
+
+    // and the formation helpers don't fall over if one gets through anyway
+    let slots = formation_slots(Point3::new(f32::NAN, 0.0, 0.0), 3, 2.5, &grid);
+    assign_slots(&[Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0)], &slots);
+}